@@ -0,0 +1,43 @@
+use crate::Error;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Requests a running operation to stop, which then fails with
+/// `Error::Cancelled`.
+///
+/// The flag is only checked between commands, so the device is always at a
+/// clean command boundary and the connection stays usable after an abort.
+#[derive(Debug, Clone, Default)]
+pub struct AbortHandle(Arc<AtomicBool>);
+
+impl AbortHandle {
+    pub fn abort(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Clear a previous abort so the next operation can run
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.is_aborted() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Share a flag the embedder already uses for cancellation
+impl From<Arc<AtomicBool>> for AbortHandle {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        AbortHandle(flag)
+    }
+}
@@ -0,0 +1,72 @@
+//! Load a full device image from a single tar archive.
+//!
+//! The archive must contain a `manifest.toml` describing each segment:
+//!
+//! ```toml
+//! [[segment]]
+//! file = "boot2.bin"
+//! address = 0x0
+//! sha256 = "..."
+//! ```
+use crate::{elf::RomSegment, Error};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, io::Read};
+
+pub const MANIFEST_NAME: &str = "manifest.toml";
+
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    #[serde(rename = "segment")]
+    pub segments: Vec<ManifestSegment>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManifestSegment {
+    pub file: String,
+    pub address: u32,
+    /// Optional hex encoded sha256 of the file
+    pub sha256: Option<String>,
+}
+
+pub fn load_bundle(reader: impl Read) -> Result<Vec<RomSegment<'static>>, Error> {
+    let mut archive = tar::Archive::new(reader);
+    let mut files = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.insert(path.trim_start_matches("./").to_string(), data);
+    }
+
+    let manifest = files
+        .get(MANIFEST_NAME)
+        .ok_or_else(|| Error::BundleFileMissing(MANIFEST_NAME.to_string()))?;
+    let manifest: Manifest = toml::from_slice(manifest)?;
+
+    let mut segments = Vec::with_capacity(manifest.segments.len());
+    for segment in manifest.segments {
+        let data = files
+            .get(&segment.file)
+            .ok_or_else(|| Error::BundleFileMissing(segment.file.clone()))?;
+        if let Some(expected) = &segment.sha256 {
+            let hash = hex::encode(Sha256::digest(data));
+            if !hash.eq_ignore_ascii_case(expected) {
+                return Err(Error::BundleChecksumMismatch(segment.file));
+            }
+        }
+        log::debug!(
+            "Bundle segment {} addr: {:x} size: {}",
+            segment.file,
+            segment.address,
+            data.len()
+        );
+        segments.push(RomSegment::from_vec(segment.address, data.clone()));
+    }
+
+    Ok(segments)
+}
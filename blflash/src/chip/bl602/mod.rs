@@ -1,19 +1,32 @@
-use super::{Chip, CodeSegment, RomSegment};
+use super::{Chip, CodeSegment, FlashCapabilities, RomSegment};
 use crate::{
     image::{BootHeaderCfg, PartitionCfg},
     Error,
 };
 use deku::prelude::*;
+use std::ops::Range;
 
 pub const DEFAULT_PARTITION_CFG: &'static [u8] = include_bytes!("cfg/partition_cfg_2M.toml");
 pub const DEFAULT_BOOTHEADER_CFG: &'static [u8] = include_bytes!("cfg/efuse_bootheader_cfg.conf");
 pub const RO_PARAMS: &'static [u8] = include_bytes!("cfg/ro_params.dtb");
 pub const BLSP_BOOT2: &'static [u8] = include_bytes!("image/blsp_boot2.bin");
 pub const EFLASH_LOADER: &'static [u8] = include_bytes!("image/eflash_loader_40m.bin");
+const EFLASH_LOADER_SHA256: [u8; 32] = [
+    0xfe, 0x27, 0xc9, 0xc4, 0xba, 0xf4, 0xd4, 0x95, 0x8c, 0xb9, 0x70, 0x8e, 0x41, 0xe5, 0xfa, 0x72,
+    0xad, 0x6a, 0x36, 0x79, 0xf1, 0x02, 0x78, 0x76, 0x1a, 0xf7, 0x3b, 0x99, 0x39, 0xfd, 0x54, 0x4b,
+];
 const ROM_START: u32 = 0x23000000;
 // 16MB
 const ROM_END: u32 = 0x23000000 + 0x1000000;
 
+// the boot header
+const PROTECTED_RANGES: &[Range<u32>] = &[0..0x1000];
+
+// NIO, DO, QO, DIO, QIO
+const IO_MODES: &[u8] = &[0, 1, 2, 3, 4];
+// 120M, XCLK, 48M, 80M, BCLK, 96M
+const FLASH_CLK_TYPES: &[u8] = &[0, 1, 2, 3, 4, 5];
+
 #[derive(Copy, Clone)]
 pub struct Bl602;
 
@@ -24,14 +37,37 @@ impl Bl602 {
 }
 
 impl Chip for Bl602 {
+    fn name(&self) -> &'static str {
+        "BL602"
+    }
+
     fn target(&self) -> &'static str {
         "riscv32imac-unknown-none-elf"
     }
 
+    fn bootrom_versions(&self) -> &'static [u32] {
+        &[1]
+    }
+
     fn get_eflash_loader(&self) -> &[u8] {
         EFLASH_LOADER
     }
 
+    fn eflash_loader_sha256(&self) -> Option<[u8; 32]> {
+        Some(EFLASH_LOADER_SHA256)
+    }
+
+    fn protected_ranges(&self) -> &'static [Range<u32>] {
+        PROTECTED_RANGES
+    }
+
+    fn flash_capabilities(&self) -> Option<FlashCapabilities> {
+        Some(FlashCapabilities {
+            io_modes: IO_MODES,
+            flash_clk_types: FLASH_CLK_TYPES,
+        })
+    }
+
     fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
         if self.addr_is_flash(code_segment.addr) {
             Some(RomSegment::from_code_segment(
@@ -50,6 +86,9 @@ impl Chip for Bl602 {
         ro_params: Vec<u8>,
         bin: &[u8],
     ) -> Result<Vec<RomSegment>, Error> {
+        if let Some(caps) = self.flash_capabilities() {
+            bootheader_cfg.validate(&caps)?;
+        }
         partition_cfg.update()?;
         let partition_cfg = partition_cfg.to_bytes()?;
 
@@ -57,10 +96,10 @@ impl Chip for Bl602 {
         let fw_image = bootheader_cfg.make_image(0x1000, Vec::from(bin))?;
 
         let segments = vec![
-            RomSegment::from_vec(0x0, boot2image),
-            RomSegment::from_vec(0xe000, partition_cfg.clone()),
-            RomSegment::from_vec(0xf000, partition_cfg),
-            RomSegment::from_vec(0x10000, fw_image),
+            RomSegment::from_vec(self.default_boot2_addr(), boot2image),
+            RomSegment::from_vec(self.default_partition_addr(), partition_cfg.clone()),
+            RomSegment::from_vec(self.default_partition_backup_addr(), partition_cfg),
+            RomSegment::from_vec(self.default_fw_addr(), fw_image),
             // TODO: generate from dts
             RomSegment::from_vec(0x1f8000, ro_params),
         ];
@@ -0,0 +1,115 @@
+use super::{Chip, CodeSegment, FlashCapabilities, RomSegment};
+use crate::{
+    image::{BootHeaderCfg, PartitionCfg},
+    Error,
+};
+use deku::prelude::*;
+use std::ops::Range;
+
+const ROM_START: u32 = 0x23000000;
+// 16MB
+const ROM_END: u32 = 0x23000000 + 0x1000000;
+
+// the boot header
+const PROTECTED_RANGES: &[Range<u32>] = &[0..0x1000];
+
+// NIO, DO, QO, DIO, QIO
+const IO_MODES: &[u8] = &[0, 1, 2, 3, 4];
+// 144M, XCLK, 57M, 72M, BCLK, 96M
+const FLASH_CLK_TYPES: &[u8] = &[0, 1, 2, 3, 4, 5];
+
+/// BL702, whose bootrom speaks the same ISP protocol as the BL602 with the
+/// same 176 byte boot header and boot info layout.
+///
+/// Only available to library users, the CLI drives the BL602. blflash does
+/// not ship the BL702 eflash_loader and boot2 images, so the caller passes
+/// the ones from Bouffalo's SDK to [`Bl702::new`], e.g. with
+/// `include_bytes!`.
+#[derive(Copy, Clone)]
+pub struct Bl702 {
+    eflash_loader: &'static [u8],
+    boot2: &'static [u8],
+}
+
+impl Bl702 {
+    /// `eflash_loader` is sent by [`crate::Flasher::load_eflash_loader`] and
+    /// `boot2` is flashed by `with_boot2`.
+    pub fn new(eflash_loader: &'static [u8], boot2: &'static [u8]) -> Self {
+        Bl702 {
+            eflash_loader,
+            boot2,
+        }
+    }
+
+    fn addr_is_flash(&self, addr: u32) -> bool {
+        (ROM_START..ROM_END).contains(&addr)
+    }
+}
+
+impl Chip for Bl702 {
+    fn name(&self) -> &'static str {
+        "BL702"
+    }
+
+    fn target(&self) -> &'static str {
+        "riscv32imac-unknown-none-elf"
+    }
+
+    fn bootrom_versions(&self) -> &'static [u32] {
+        // not collected from real parts yet, the chip check is skipped
+        &[]
+    }
+
+    fn get_eflash_loader(&self) -> &[u8] {
+        self.eflash_loader
+    }
+
+    fn protected_ranges(&self) -> &'static [Range<u32>] {
+        PROTECTED_RANGES
+    }
+
+    fn flash_capabilities(&self) -> Option<FlashCapabilities> {
+        Some(FlashCapabilities {
+            io_modes: IO_MODES,
+            flash_clk_types: FLASH_CLK_TYPES,
+        })
+    }
+
+    fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
+        if self.addr_is_flash(code_segment.addr) {
+            Some(RomSegment::from_code_segment(
+                code_segment.addr - ROM_START,
+                code_segment,
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn with_boot2(
+        &self,
+        mut partition_cfg: PartitionCfg,
+        mut bootheader_cfg: BootHeaderCfg,
+        _ro_params: Vec<u8>,
+        bin: &[u8],
+    ) -> Result<Vec<RomSegment<'_>>, Error> {
+        if let Some(caps) = self.flash_capabilities() {
+            bootheader_cfg.validate(&caps)?;
+        }
+        partition_cfg.update()?;
+        let partition_cfg = partition_cfg.to_bytes()?;
+
+        let boot2image = bootheader_cfg.make_image(0x2000, Vec::from(self.boot2))?;
+        let fw_image = bootheader_cfg.make_image(0x1000, Vec::from(bin))?;
+
+        // the BL702 has no RF, so there are no ro_params to flash
+        let segments = vec![
+            RomSegment::from_vec(self.default_boot2_addr(), boot2image),
+            RomSegment::from_vec(self.default_partition_addr(), partition_cfg.clone()),
+            RomSegment::from_vec(self.default_partition_backup_addr(), partition_cfg),
+            RomSegment::from_vec(self.default_fw_addr(), fw_image),
+        ];
+
+        Ok(segments)
+    }
+}
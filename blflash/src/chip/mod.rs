@@ -1,12 +1,93 @@
 pub mod bl602;
+pub mod bl702;
+mod quirks;
 pub use crate::elf::{CodeSegment, FirmwareImage, RomSegment};
 use crate::image::{BootHeaderCfg, PartitionCfg};
 use crate::Error;
 pub use bl602::Bl602;
+pub use bl702::Bl702;
+pub use quirks::{board_quirks, ChipQuirks, ResetStrategy, BOARDS};
+use std::ops::Range;
+
+/// Flash IO modes and clocks the chip's bootrom can boot from.
+///
+/// The bootrom does not report these in boot info, so they come from the
+/// chip definition.
+#[derive(Debug, Clone, Copy)]
+pub struct FlashCapabilities {
+    /// Accepted boot header `io_mode` values
+    pub io_modes: &'static [u8],
+    /// Accepted boot header `flash_clk_type` values
+    pub flash_clk_types: &'static [u8],
+}
+
+/// Bootrom opcodes that check and then start the uploaded eflash_loader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoaderStart {
+    pub check_image: u8,
+    pub run_image: u8,
+}
+
+impl Default for LoaderStart {
+    fn default() -> Self {
+        LoaderStart {
+            check_image: 0x19,
+            run_image: 0x1a,
+        }
+    }
+}
 
 pub trait Chip {
+    /// Human readable chip name, used in logs
+    fn name(&self) -> &'static str {
+        "unknown chip"
+    }
     fn target(&self) -> &'static str;
+    /// Bootrom versions reported in boot info by this chip, empty if unknown
+    fn bootrom_versions(&self) -> &'static [u32] {
+        &[]
+    }
     fn get_eflash_loader(&self) -> &[u8];
+    /// sha256 of the whole `get_eflash_loader` image, if known
+    fn eflash_loader_sha256(&self) -> Option<[u8; 32]> {
+        None
+    }
+    fn flash_capabilities(&self) -> Option<FlashCapabilities> {
+        None
+    }
+    fn quirks(&self) -> ChipQuirks {
+        ChipQuirks::default()
+    }
+    /// How the bootrom is told to start the eflash_loader, for loaders
+    /// with a different startup sequence
+    fn loader_start(&self) -> LoaderStart {
+        LoaderStart::default()
+    }
+    /// Flash offset of boot2 in the SDK's default layout
+    fn default_boot2_addr(&self) -> u32 {
+        0x0
+    }
+    /// Flash offset of the partition table in the SDK's default layout
+    fn default_partition_addr(&self) -> u32 {
+        0xe000
+    }
+    /// Flash offset of the backup copy of the partition table
+    fn default_partition_backup_addr(&self) -> u32 {
+        0xf000
+    }
+    /// Flash offset of the firmware in the SDK's default layout
+    fn default_fw_addr(&self) -> u32 {
+        0x10000
+    }
+    /// Size of the eFuse region in bytes
+    fn efuse_size(&self) -> u32 {
+        crate::flasher::protocol::EFUSE_SIZE
+    }
+    /// Flash ranges that aren't written once protected, see
+    /// [`crate::Flasher::protect_chip_ranges`]
+    fn protected_ranges(&self) -> &'static [Range<u32>] {
+        &[]
+    }
     fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>>;
     fn with_boot2(
         &self,
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+/// Per-board behavior tweaks consulted throughout the flashing flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipQuirks {
+    /// Delay after each reset line change
+    pub reset_delay: Duration,
+    /// Delay after running the eflash_loader before handshaking with it
+    pub loader_start_delay: Duration,
+    /// Pin toggling used by the reset sequences
+    pub reset_strategy: ResetStrategy,
+}
+
+impl Default for ChipQuirks {
+    fn default() -> Self {
+        ChipQuirks {
+            reset_delay: Duration::from_millis(50),
+            loader_start_delay: Duration::from_millis(500),
+            reset_strategy: ResetStrategy::Classic,
+        }
+    }
+}
+
+/// How the DTR (reset) and RTS (boot) lines are toggled to enter the bootrom
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ResetStrategy {
+    /// RTS drives BOOT and DTR drives reset
+    #[default]
+    Classic,
+    /// Boards that wire BOOT to DTR and reset to RTS
+    Inverted,
+    /// Classic wiring with the order and settle time chosen by the caller.
+    /// With `boot_high_first` BOOT is raised before pulsing reset,
+    /// otherwise reset is asserted first and BOOT raised while it is held.
+    Custom { boot_high_first: bool, hold_ms: u64 },
+    /// No reset lines, e.g. over a TCP bridge. The device must be put into
+    /// the bootrom by hand and resetting fails with `Error::ResetUnavailable`.
+    Manual,
+}
+
+/// Known boards and their quirks
+pub const BOARDS: &[(&str, ChipQuirks)] = &[(
+    // Pine64 PineCone: only TX and RX reach the CH340, the BOOT pin (IO8)
+    // is a jumper and EN a push button, so the user resets by hand
+    "pinecone",
+    ChipQuirks {
+        reset_delay: Duration::from_millis(50),
+        loader_start_delay: Duration::from_millis(500),
+        reset_strategy: ResetStrategy::Manual,
+    },
+)];
+
+pub fn board_quirks(board: &str) -> Option<ChipQuirks> {
+    BOARDS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(board))
+        .map(|(_, quirks)| *quirks)
+}
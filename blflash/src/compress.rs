@@ -0,0 +1,99 @@
+//! Transparent decompression of gzip and xz compressed firmware, enabled
+//! by the `gzip` and `xz` features.
+use crate::Error;
+use std::borrow::Cow;
+#[cfg(any(feature = "gzip", feature = "xz"))]
+use std::io::Read;
+
+#[cfg(feature = "gzip")]
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+#[cfg(feature = "xz")]
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+/// Entropy is sampled from at most this many leading bytes.
+const ENTROPY_SAMPLE: usize = 0x10000;
+/// Already compressed or encrypted data is close to 8 bits per byte.
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+/// Shannon entropy of `data` in bits per byte, sampled from at most the
+/// first 64KB.
+pub fn entropy(data: &[u8]) -> f64 {
+    let sample = &data[..data.len().min(ENTROPY_SAMPLE)];
+    if sample.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &b in sample {
+        counts[b as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Whether compressing `data` is likely to pay off.
+pub fn is_compressible(data: &[u8]) -> bool {
+    entropy(data) < INCOMPRESSIBLE_ENTROPY
+}
+
+/// Inflate `data` if it starts with the magic of an enabled format,
+/// otherwise return it unchanged. Data that looks compressed or encrypted
+/// but matches no enabled format is flashed as is, with a warning.
+pub fn decompress(data: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    #[cfg(feature = "gzip")]
+    if data.starts_with(GZIP_MAGIC) {
+        log::trace!("Detect gzip");
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(data).read_to_end(&mut out)?;
+        return Ok(Cow::Owned(out));
+    }
+    #[cfg(feature = "xz")]
+    if data.starts_with(XZ_MAGIC) {
+        log::trace!("Detect xz");
+        let mut out = Vec::new();
+        xz2::read::XzDecoder::new(data).read_to_end(&mut out)?;
+        return Ok(Cow::Owned(out));
+    }
+    if !is_compressible(data) {
+        log::warn!(
+            "Image has {:.2} bits of entropy per byte but is not in an enabled compressed format, flashing it as is",
+            entropy(data)
+        );
+    }
+    Ok(Cow::Borrowed(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entropy_of_constant_data_is_zero() {
+        assert_eq!(entropy(&[]), 0.0);
+        assert_eq!(entropy(&[0u8; 4096]), 0.0);
+    }
+
+    #[test]
+    fn entropy_of_uniform_data_is_eight() {
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        assert!((entropy(&data) - 8.0).abs() < 1e-9);
+        assert!(!is_compressible(&data));
+    }
+
+    #[test]
+    fn text_is_compressible() {
+        let data = b"fn main() { println!(\"hello\"); }\n".repeat(64);
+        assert!(is_compressible(&data));
+    }
+
+    #[test]
+    fn decompress_passes_incompressible_data_through() {
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        assert_eq!(&*decompress(&data).unwrap(), &data[..]);
+    }
+}
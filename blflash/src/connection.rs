@@ -1,11 +1,16 @@
 #![macro_use]
 
-use crate::{Error, RomError};
+use crate::{
+    chip::{ChipQuirks, ResetStrategy},
+    Error, RomError,
+};
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use deku::prelude::*;
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, ErrorKind, Read, Write};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use serial::{BaudRate, SerialPort, SerialPortSettings};
 
@@ -56,11 +61,45 @@ pub trait Command: DekuContainerWrite {
     fn checksum(&self) -> u8 {
         0
     }
+    /// The framed bytes sent for this command, without sending it
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        let data = Vec::new();
+        let mut writer = Cursor::new(data);
+        let body = self.to_bytes()?;
+        let len = body.len() as u16;
+
+        writer.write_u8(Self::CMD_ID)?;
+        writer.write_u8(self.checksum())?;
+        writer.write_u16::<LittleEndian>(len)?;
+        writer.write_all(&body)?;
+
+        Ok(writer.into_inner())
+    }
+}
+
+/// Direction of the bytes passed to a packet hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Send,
+    Receive,
 }
 
+/// Sees every byte written to and read from the serial port, before
+/// responses are parsed.
+pub type PacketHook = Box<dyn FnMut(Direction, &[u8])>;
+
 pub struct Connection {
     serial: Box<dyn SerialPort>,
     baud_rate: Option<BaudRate>,
+    quirks: ChipQuirks,
+    received: u64,
+    packet_hook: Option<PacketHook>,
+    #[cfg(target_os = "linux")]
+    raw_fd: Option<RawFd>,
+    rate_limit: Option<u32>,
+    next_packet: Option<Instant>,
+    throttled: u64,
+    throttled_since: Option<Instant>,
 }
 
 impl Connection {
@@ -68,6 +107,131 @@ impl Connection {
         Connection {
             serial: Box::new(serial),
             baud_rate: None,
+            quirks: ChipQuirks::default(),
+            received: 0,
+            packet_hook: None,
+            #[cfg(target_os = "linux")]
+            raw_fd: None,
+            rate_limit: None,
+            next_packet: None,
+            throttled: 0,
+            throttled_since: None,
+        }
+    }
+
+    /// Cap data packets to `bytes_per_sec` by sleeping between them, for
+    /// serial bridges that corrupt data at sustained high rates. Off by
+    /// default.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: Option<u32>) {
+        self.rate_limit = bytes_per_sec.filter(|&rate| rate > 0);
+        self.next_packet = None;
+    }
+
+    pub fn rate_limit(&self) -> Option<u32> {
+        self.rate_limit
+    }
+
+    /// Wait until the previous data packet's share of the rate limit has
+    /// passed, then account for a packet of `len` bytes.
+    pub fn throttle(&mut self, len: usize) {
+        let rate = match self.rate_limit {
+            Some(rate) => rate,
+            None => return,
+        };
+        let now = Instant::now();
+        if let Some(next) = self.next_packet {
+            if next > now {
+                sleep(next - now);
+            }
+        }
+        let start = self.next_packet.map_or(now, |next| next.max(now));
+        self.next_packet = Some(start + Duration::from_secs_f64(len as f64 / rate as f64));
+        self.throttled_since.get_or_insert(start);
+        self.throttled += len as u64;
+    }
+
+    /// Bytes per second actually achieved by the data packets sent under
+    /// the rate limit since the previous call, `None` if there were none.
+    pub fn take_throttled_rate(&mut self) -> Option<u64> {
+        let since = self.throttled_since.take()?;
+        let bytes = std::mem::take(&mut self.throttled);
+        let elapsed = since.elapsed();
+        Some((bytes as f64 / elapsed.as_millis().max(1) as f64 * 1000.0) as u64)
+    }
+
+    /// The tty file descriptor of the serial port, which lets
+    /// [`Connection::set_baud_raw`] set baud rates outside the standard
+    /// ones. It must stay owned by the port passed to `new`.
+    #[cfg(target_os = "linux")]
+    pub fn set_raw_fd(&mut self, fd: RawFd) {
+        self.raw_fd = Some(fd);
+    }
+
+    /// Baud rate last set with `set_baud`, `None` if the port's own
+    /// setting was never changed
+    pub fn current_baud(&self) -> Option<BaudRate> {
+        self.baud_rate
+    }
+
+    pub fn current_timeout(&self) -> Duration {
+        self.serial.timeout()
+    }
+
+    /// Total bytes received so far
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn set_packet_hook(&mut self, hook: Option<PacketHook>) {
+        self.packet_hook = hook;
+    }
+
+    fn on_packet(&mut self, direction: Direction, data: &[u8]) {
+        if let Some(hook) = &mut self.packet_hook {
+            hook(direction, data);
+        }
+    }
+
+    pub fn set_quirks(&mut self, quirks: ChipQuirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_reset_strategy(&mut self, strategy: ResetStrategy) {
+        self.quirks.reset_strategy = strategy;
+    }
+
+    fn reset_delay(&self) -> Duration {
+        match self.quirks.reset_strategy {
+            ResetStrategy::Custom { hold_ms, .. } => Duration::from_millis(hold_ms),
+            _ => self.quirks.reset_delay,
+        }
+    }
+
+    fn set_rts(&mut self, level: bool) -> Result<(), Error> {
+        self.serial.set_rts(level)?;
+        sleep(self.reset_delay());
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error> {
+        self.serial.set_dtr(level)?;
+        sleep(self.reset_delay());
+        Ok(())
+    }
+
+    /// Drive the BOOT pin
+    fn set_boot(&mut self, level: bool) -> Result<(), Error> {
+        match self.quirks.reset_strategy {
+            ResetStrategy::Inverted => self.set_dtr(level),
+            _ => self.set_rts(level),
+        }
+    }
+
+    /// Drive the reset pin
+    fn set_reset(&mut self, level: bool) -> Result<(), Error> {
+        match self.quirks.reset_strategy {
+            ResetStrategy::Inverted => self.set_rts(level),
+            _ => self.set_dtr(level),
         }
     }
 
@@ -76,25 +240,33 @@ impl Connection {
     }
 
     pub fn reset(&mut self) -> Result<(), Error> {
-        self.serial.set_rts(false)?;
-        sleep(Duration::from_millis(50));
-        self.serial.set_dtr(true)?;
-        sleep(Duration::from_millis(50));
-        self.serial.set_dtr(false)?;
-        sleep(Duration::from_millis(50));
+        if self.quirks.reset_strategy == ResetStrategy::Manual {
+            return Err(Error::ResetUnavailable);
+        }
+        self.set_boot(false)?;
+        self.set_reset(true)?;
+        self.set_reset(false)?;
 
         Ok(())
     }
 
     pub fn reset_to_flash(&mut self) -> Result<(), Error> {
-        self.serial.set_rts(true)?;
-        sleep(Duration::from_millis(50));
-        self.serial.set_dtr(true)?;
-        sleep(Duration::from_millis(50));
-        self.serial.set_dtr(false)?;
-        sleep(Duration::from_millis(50));
-        self.serial.set_rts(false)?;
-        sleep(Duration::from_millis(50));
+        if self.quirks.reset_strategy == ResetStrategy::Manual {
+            return Err(Error::ResetUnavailable);
+        }
+        if let ResetStrategy::Custom {
+            boot_high_first: false,
+            ..
+        } = self.quirks.reset_strategy
+        {
+            self.set_reset(true)?;
+            self.set_boot(true)?;
+        } else {
+            self.set_boot(true)?;
+            self.set_reset(true)?;
+        }
+        self.set_reset(false)?;
+        self.set_boot(false)?;
 
         Ok(())
     }
@@ -111,10 +283,34 @@ impl Connection {
         Ok(())
     }
 
-    pub fn with_timeout<T, F: FnMut(&mut Connection) -> Result<T, Error>>(
+    /// Switch the baud rate once everything queued has been sent, then
+    /// discard whatever arrives garbled while the line settles.
+    pub fn set_baud_and_drain(&mut self, speed: BaudRate) -> Result<usize, Error> {
+        const SETTLE: Duration = Duration::from_millis(5);
+
+        self.flush()?;
+        self.set_baud(speed)?;
+        sleep(SETTLE);
+        self.clear_input()
+    }
+
+    /// Set any integer baud rate, with the termios2 ioctl on Linux when the
+    /// port's fd is known, and like `set_baud` otherwise. `set_baud` itself
+    /// never uses the ioctl.
+    pub fn set_baud_raw(&mut self, rate: u32) -> Result<(), Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(fd) = self.raw_fd {
+            set_custom_baud(fd, rate)?;
+            self.baud_rate = Some(BaudRate::BaudOther(rate as usize));
+            return Ok(());
+        }
+        self.set_baud(BaudRate::from_speed(rate as usize))
+    }
+
+    pub fn with_timeout<T, F: FnOnce(&mut Connection) -> Result<T, Error>>(
         &mut self,
         timeout: Duration,
-        mut f: F,
+        f: F,
     ) -> Result<T, Error> {
         let old_timeout = self.serial.timeout();
         self.serial.set_timeout(timeout)?;
@@ -125,21 +321,52 @@ impl Connection {
 
     fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, Error> {
         let mut buf = vec![0u8; len];
-        self.serial.read_exact(&mut buf)?;
+        match self.serial.read_exact(&mut buf) {
+            Err(e) if e.kind() == ErrorKind::TimedOut => return Err(Error::Timeout),
+            result => result?,
+        }
+        self.received += len as u64;
+        self.on_packet(Direction::Receive, &buf);
         Ok(buf)
     }
 
     pub fn read_response(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        self.read_response_status()?;
+        if len > 0 {
+            self.read_exact(len)
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    /// Read a variable length response. `remaining` is given the payload
+    /// received so far and returns how many more bytes it needs, 0 once the
+    /// response is complete.
+    pub fn read_response_with<F: FnMut(&[u8]) -> usize>(
+        &mut self,
+        mut remaining: F,
+    ) -> Result<Vec<u8>, Error> {
+        const MAX_RESPONSE_LEN: usize = 2 + u16::MAX as usize;
+
+        self.read_response_status()?;
+        let mut buf = vec![];
+        loop {
+            let more = remaining(&buf);
+            if more == 0 {
+                return Ok(buf);
+            }
+            if buf.len() + more > MAX_RESPONSE_LEN {
+                return Err(Error::OverSizedPacket);
+            }
+            buf.extend(self.read_exact(more)?);
+        }
+    }
+
+    fn read_response_status(&mut self) -> Result<(), Error> {
         let resp = self.read_exact(2)?;
         match &resp[0..2] {
             // OK
-            [0x4f, 0x4b] => {
-                if len > 0 {
-                    self.read_exact(len)
-                } else {
-                    Ok(vec![])
-                }
-            }
+            [0x4f, 0x4b] => Ok(()),
             // FL
             [0x46, 0x4c] => {
                 let code = self.read_exact(2)?;
@@ -154,12 +381,43 @@ impl Connection {
         }
     }
 
+    /// Read whatever arrives within `timeout`, up to `max` bytes.
+    pub fn read_pending(&mut self, timeout: Duration, max: usize) -> Result<Vec<u8>, Error> {
+        self.with_timeout(timeout, |connection| {
+            let mut data = vec![];
+            let mut buf = [0u8; 256];
+            while data.len() < max {
+                match connection.serial.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        connection.received += n as u64;
+                        connection.on_packet(Direction::Receive, &buf[..n]);
+                        data.extend_from_slice(&buf[..n]);
+                    }
+                    Err(e) if e.kind() == ErrorKind::TimedOut => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(data)
+        })
+    }
+
+    /// Drop bytes received but not read yet, returning how many.
+    pub fn clear_input(&mut self) -> Result<usize, Error> {
+        const MAX_STALE: usize = 0x10000;
+
+        let stale = self.read_pending(Duration::from_millis(10), MAX_STALE)?;
+        log::trace!("Cleared {} stale input bytes", stale.len());
+        Ok(stale.len())
+    }
+
     pub fn calc_duration_length(&mut self, duration: Duration) -> usize {
         self.baud_rate.unwrap_or(DEFAULT_BAUDRATE).speed() / 10 / 1000
             * (duration.as_millis() as usize)
     }
 
     pub fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.on_packet(Direction::Send, buf);
         Ok(self.serial.write_all(buf)?)
     }
 
@@ -167,35 +425,142 @@ impl Connection {
         Ok(self.serial.flush()?)
     }
 
-    pub fn command<C: Command>(&mut self, command: C) -> Result<C::Response, Error> {
-        let req = self.to_cmd(command)?;
+    /// Send a raw command framed with `opcode`, a zero checksum and the
+    /// payload length, and read `expected_resp_len` bytes after the OK
+    /// status. `opcode` is a `u8` because the frame has a single opcode
+    /// byte, followed by the checksum byte.
+    pub fn send_command(
+        &mut self,
+        opcode: u8,
+        payload: &[u8],
+        expected_resp_len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut req = Vec::with_capacity(4 + payload.len());
+        req.write_u8(opcode)?;
+        req.write_u8(0)?;
+        req.write_u16::<LittleEndian>(payload.len() as u16)?;
+        req.extend_from_slice(payload);
         self.write_all(&req)?;
         self.flush()?;
 
+        self.read_response(expected_resp_len).map_err(|e| match e {
+            Error::RomError(code) => Error::CommandFailed { opcode, code },
+            e => e,
+        })
+    }
+
+    pub fn command<C: Command>(&mut self, command: C) -> Result<C::Response, Error> {
+        self.write_command(command)?;
+        self.read_command_response::<C>()
+    }
+
+    /// Send a command without waiting for its response, which must be read
+    /// with [`Connection::read_command_response`] in order.
+    pub fn write_command<C: Command>(&mut self, command: C) -> Result<(), Error> {
+        let req = command.encode()?;
+        self.write_all(&req)?;
+        self.flush()
+    }
+
+    pub fn read_command_response<C: Command>(&mut self) -> Result<C::Response, Error> {
+        let with_opcode = |e: Error| match e {
+            Error::RomError(code) => Error::CommandFailed {
+                opcode: C::CMD_ID,
+                code,
+            },
+            e => e,
+        };
         Ok(if let Some(resp) = C::Response::no_response_payload() {
-            self.read_response(0)?;
+            self.read_response(0).map_err(with_opcode)?;
             resp
         } else {
-            let len = LittleEndian::read_u16(&self.read_response(2)?);
-            let buf = Vec::new();
-            let mut writer = Cursor::new(buf);
-            writer.write_u16::<LittleEndian>(len)?;
-            writer.write_all(&self.read_exact(len as usize)?)?;
-            C::Response::from_payload(&writer.into_inner())?
+            let bytes = self
+                .read_response_with(length_prefixed)
+                .map_err(with_opcode)?;
+            C::Response::from_payload(&bytes).map_err(|e| {
+                let context = std::any::type_name::<C::Response>();
+                let context = context.rsplit("::").next().unwrap_or(context);
+                log::debug!("Failed to parse {}: {}", context, e);
+                Error::ResponseParse { context, bytes }
+            })?
         })
     }
+}
 
-    fn to_cmd<C: Command>(&self, command: C) -> Result<Vec<u8>, Error> {
-        let data = Vec::new();
-        let mut writer = Cursor::new(data);
-        let body = command.to_bytes()?;
-        let len = body.len() as u16;
+/// Bytes still missing from a payload that starts with a u16 length of the
+/// data following it, for [`Connection::read_response_with`]
+fn length_prefixed(received: &[u8]) -> usize {
+    if received.len() < 2 {
+        return 2 - received.len();
+    }
+    (2 + LittleEndian::read_u16(received) as usize).saturating_sub(received.len())
+}
 
-        writer.write_u8(C::CMD_ID)?;
-        writer.write_u8(command.checksum())?;
-        writer.write_u16::<LittleEndian>(len)?;
-        writer.write_all(&body)?;
+#[cfg(target_os = "linux")]
+fn set_custom_baud(fd: RawFd, rate: u32) -> std::io::Result<()> {
+    // SAFETY: termios2 is plain data, and the ioctls only access it
+    // through the pointers passed.
+    unsafe {
+        let mut tio: libc::termios2 = std::mem::zeroed();
+        if libc::ioctl(fd, libc::TCGETS2 as _, &mut tio) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        tio.c_cflag &= !libc::CBAUD;
+        tio.c_cflag |= libc::BOTHER;
+        tio.c_ispeed = rate;
+        tio.c_ospeed = rate;
+        if libc::ioctl(fd, libc::TCSETS2 as _, &tio) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
 
-        Ok(writer.into_inner())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flasher::protocol;
+    use std::net::TcpListener;
+
+    /// A connection reading `reply` from a local TCP socket
+    fn replaying(reply: &'static [u8]) -> Connection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let serial = crate::TcpSerial::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(reply).unwrap();
+        Connection::new(serial)
+    }
+
+    #[test]
+    fn length_prefixed_counts_missing_bytes() {
+        assert_eq!(length_prefixed(&[]), 2);
+        assert_eq!(length_prefixed(&[3]), 1);
+        assert_eq!(length_prefixed(&[3, 0]), 3);
+        assert_eq!(length_prefixed(&[3, 0, 1, 2]), 1);
+        assert_eq!(length_prefixed(&[3, 0, 1, 2, 3]), 0);
+        assert_eq!(length_prefixed(&[0, 0]), 0);
+    }
+
+    #[test]
+    fn read_response_with_stops_when_complete() {
+        let mut connection = replaying(b"OK\x03\x00abcOK");
+        let payload = connection.read_response_with(length_prefixed).unwrap();
+        assert_eq!(payload, b"\x03\x00abc");
+        // the next response is left unread
+        assert!(connection.read_response(0).is_ok());
+    }
+
+    #[test]
+    fn variable_length_responses_parse() {
+        let mut connection = replaying(b"OK\x04\x00\x01\x02\x03\x04FL\x05\x00");
+        let resp = connection
+            .read_command_response::<protocol::FlashRead>()
+            .unwrap();
+        assert_eq!(resp.len, 4);
+        assert_eq!(resp.data, vec![1, 2, 3, 4]);
+        assert!(matches!(
+            connection.read_command_response::<protocol::EfuseRead>(),
+            Err(Error::CommandFailed { opcode: 0x41, .. })
+        ));
     }
 }
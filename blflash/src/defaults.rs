@@ -0,0 +1,63 @@
+//! Built-in tunables, overridable through the environment.
+//!
+//! Precedence is: explicit arguments > environment > built-in default.
+use serial::BaudRate;
+use std::{env, str::FromStr, time::Duration};
+
+pub const CONNECT_RETRIES_ENV: &str = "BLFLASH_CONNECT_RETRIES";
+pub const TIMEOUT_MS_ENV: &str = "BLFLASH_TIMEOUT_MS";
+pub const CHUNK_SIZE_ENV: &str = "BLFLASH_CHUNK_SIZE";
+pub const BAUD_RATE_ENV: &str = "BLFLASH_BAUD_RATE";
+pub const INITIAL_BAUD_RATE_ENV: &str = "BLFLASH_INITIAL_BAUD_RATE";
+
+const CONNECT_RETRIES: u32 = 10;
+const TIMEOUT: Duration = Duration::from_secs(10);
+const CHUNK_SIZE: usize = 4000;
+const BAUD_RATE: usize = 1_000_000;
+const INITIAL_BAUD_RATE: usize = 115200;
+
+fn from_env<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            log::warn!("Ignoring invalid {}={:?}", name, value);
+            None
+        }
+    }
+}
+
+pub fn connect_retries() -> u32 {
+    from_env(CONNECT_RETRIES_ENV).unwrap_or(CONNECT_RETRIES)
+}
+
+pub fn timeout() -> Duration {
+    from_env(TIMEOUT_MS_ENV)
+        .map(Duration::from_millis)
+        .unwrap_or(TIMEOUT)
+}
+
+/// Bytes sent per `flash_program` command
+pub fn chunk_size() -> usize {
+    from_env(CHUNK_SIZE_ENV)
+        .filter(|&size| size > 0 && size <= crate::flasher::protocol::MAX_CHUNK_SIZE)
+        .unwrap_or(CHUNK_SIZE)
+}
+
+/// Baud rate used with the eflash_loader
+pub fn baud_rate() -> BaudRate {
+    BaudRate::from_speed(
+        from_env::<usize>(BAUD_RATE_ENV)
+            .filter(|&rate| rate > 0)
+            .unwrap_or(BAUD_RATE),
+    )
+}
+
+/// Baud rate of the bootrom handshake
+pub fn initial_baud_rate() -> BaudRate {
+    BaudRate::from_speed(
+        from_env::<usize>(INITIAL_BAUD_RATE_ENV)
+            .filter(|&rate| rate > 0)
+            .unwrap_or(INITIAL_BAUD_RATE),
+    )
+}
@@ -5,6 +5,7 @@ use xmas_elf::program::{SegmentData, Type};
 use xmas_elf::ElfFile;
 
 use crate::chip::Chip;
+use crate::Error;
 
 pub struct FirmwareImage<'a> {
     pub entry: u32,
@@ -114,6 +115,10 @@ impl<'a> RomSegment<'a> {
             data: Cow::Owned(data),
         }
     }
+    /// A raw binary to be written at `addr` as is
+    pub fn from_bin(addr: u32, data: Cow<'a, [u8]>) -> RomSegment<'a> {
+        RomSegment { addr, data }
+    }
     pub fn from_slice(addr: u32, data: &'a [u8]) -> RomSegment<'a> {
         RomSegment {
             addr,
@@ -126,4 +131,108 @@ impl<'a> RomSegment<'a> {
             data: Cow::Borrowed(code_segment.data),
         }
     }
+    /// Merge segments that touch or share a `block_size` erase block, in
+    /// address order, filling the gaps between them with 0xFF. Overlapping
+    /// segments must agree on the bytes they share.
+    pub fn coalesce(
+        mut segments: Vec<RomSegment<'a>>,
+        block_size: u32,
+    ) -> Result<Vec<RomSegment<'a>>, Error> {
+        segments.retain(|s| !s.data.is_empty());
+        segments.sort_by_key(|s| s.addr);
+
+        let mut merged: Vec<RomSegment<'a>> = Vec::with_capacity(segments.len());
+        for segment in segments {
+            if let Some(last) = merged.last_mut() {
+                let end = last.addr + last.size();
+                if segment.addr <= end || segment.addr / block_size == (end - 1) / block_size {
+                    if segment.addr < end {
+                        let start = (segment.addr - last.addr) as usize;
+                        let len = (end - segment.addr).min(segment.size()) as usize;
+                        if last.data[start..start + len] != segment.data[..len] {
+                            return Err(Error::SegmentOverlap(segment.addr));
+                        }
+                    }
+                    if segment.addr + segment.size() <= end {
+                        continue;
+                    }
+                    let data = last.data.to_mut();
+                    data.resize((segment.addr - last.addr) as usize, 0xFF);
+                    data.extend_from_slice(&segment.data);
+                    continue;
+                }
+            }
+            merged.push(segment);
+        }
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(segments: &[RomSegment<'_>]) -> Vec<(u32, u32)> {
+        segments.iter().map(|s| (s.addr, s.size())).collect()
+    }
+
+    #[test]
+    fn coalesce_merges_contiguous_segments() {
+        let merged = RomSegment::coalesce(
+            vec![
+                RomSegment::from_vec(0x10, vec![2; 0x10]),
+                RomSegment::from_vec(0x50, vec![]),
+                RomSegment::from_vec(0x0, vec![1; 0x10]),
+            ],
+            0x1000,
+        )
+        .unwrap();
+        assert_eq!(layout(&merged), vec![(0x0, 0x20)]);
+        assert_eq!(&merged[0].data[..0x10], &[1; 0x10][..]);
+        assert_eq!(&merged[0].data[0x10..], &[2; 0x10][..]);
+    }
+
+    #[test]
+    fn coalesce_fills_gaps_within_a_block() {
+        let merged = RomSegment::coalesce(
+            vec![
+                RomSegment::from_vec(0x0, vec![1; 0x10]),
+                RomSegment::from_vec(0x100, vec![2; 0x10]),
+                RomSegment::from_vec(0x2000, vec![3; 0x10]),
+            ],
+            0x1000,
+        )
+        .unwrap();
+        assert_eq!(layout(&merged), vec![(0x0, 0x110), (0x2000, 0x10)]);
+        assert!(merged[0].data[0x10..0x100].iter().all(|&b| b == 0xFF));
+        assert_eq!(&merged[0].data[0x100..], &[2; 0x10][..]);
+    }
+
+    #[test]
+    fn coalesce_accepts_agreeing_overlap() {
+        let merged = RomSegment::coalesce(
+            vec![
+                RomSegment::from_vec(0x0, vec![1; 0x20]),
+                RomSegment::from_vec(0x10, vec![1; 0x20]),
+                // entirely inside the merged segment
+                RomSegment::from_vec(0x8, vec![1; 0x8]),
+            ],
+            0x1000,
+        )
+        .unwrap();
+        assert_eq!(layout(&merged), vec![(0x0, 0x30)]);
+        assert!(merged[0].data.iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn coalesce_rejects_conflicting_overlap() {
+        let result = RomSegment::coalesce(
+            vec![
+                RomSegment::from_vec(0x0, vec![1; 0x20]),
+                RomSegment::from_vec(0x10, vec![2; 0x10]),
+            ],
+            0x1000,
+        );
+        assert!(matches!(result, Err(Error::SegmentOverlap(0x10))));
+    }
 }
@@ -7,26 +7,118 @@ pub enum Error {
     Serial(#[from] serial::core::Error),
     #[error("IO error: {0}")]
     IO(#[from] std::io::Error),
-    #[error("Failed to connect to the device")]
-    ConnectionFailed,
+    #[error("Failed to connect to the device after {0} attempts")]
+    ConnectionFailed(u32),
+    #[error("Failed to connect, the device appears to be running its application (console output: {0:?}). Hold the BOOT pin high while resetting to enter the bootloader")]
+    DeviceInApplication(String),
+    #[error("Failed to connect, nothing was received from the device. Check that TX and RX are crossed (adapter TX to device RX) and that the BOOT pin is held high while resetting")]
+    NoResponse,
+    #[error("Failed to connect, the handshake was echoed back. TX and RX may be shorted together")]
+    HandshakeLoopback,
+    #[error("Failed to connect at any of the baud rates {0:?}")]
+    AutobaudFailed(Vec<usize>),
+    #[error("The device can't be reset without reset lines, reset it into the bootloader by hand")]
+    ResetUnavailable,
+    #[error("The eflash_loader is not running, flash the device first or let blflash load it")]
+    LoaderNotRunning,
+    #[error("Lost sync with the device, received an unexpected response")]
+    LostSync,
     #[error("Timeout while running command")]
     Timeout,
+    #[error("Operation cancelled")]
+    Cancelled,
+    #[error("sha256 mismatch at {addr:#x}: expected {}, got {}", hex::encode(.expected), hex::encode(.actual))]
+    VerifyFailed {
+        addr: u32,
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    #[error("flash read back at {addr:#x}+{offset:#x} differs from the data written")]
+    ReadbackMismatch { addr: u32, offset: u32 },
+    #[error("flash read returned {got} of {requested} bytes")]
+    ShortRead { requested: u32, got: u32 },
+    #[error("segment header echoed back differs at {ranges:?}: sent {}, received {}", hex::encode(.sent), hex::encode(.received))]
+    SegmentHeaderMismatch {
+        ranges: Vec<std::ops::Range<usize>>,
+        sent: Vec<u8>,
+        received: Vec<u8>,
+    },
     #[error("Invalid response header")]
     RespError,
     #[error("Packet to large for buffer")]
     OverSizedPacket,
+    #[error("chunk size {0} out of range")]
+    InvalidChunkSize(usize),
+    #[error("refusing to write protected flash at {addr:#x}")]
+    ProtectedRegion { addr: u32 },
+    #[error("address {0:#x} is not aligned to a 4KB sector")]
+    SectorUnaligned(u32),
+    #[error("{0} bytes don't fit a 4KB sector")]
+    SectorOverflow(usize),
+    #[error("address {0:#x} is not 4 byte aligned")]
+    Unaligned(u32),
+    #[error("flash range {addr:#x}+{len} is out of bounds")]
+    InvalidFlashRange { addr: u32, len: u32 },
+    #[error("eFuse range {addr:#x}+{len} is out of bounds")]
+    InvalidEfuseRange { addr: u32, len: u32 },
+    #[error("eflash_loader sha256 mismatch: expected {}, got {}", hex::encode(.expected), hex::encode(.actual))]
+    LoaderVerifyFailed {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// The bootrom's check_image found that the loader it received doesn't
+    /// match the sha256 in the boot header
+    #[error(
+        "the bootrom rejected the uploaded eflash_loader with {0:?}, it was corrupted in transfer"
+    )]
+    LoaderRejected(RomError),
+    #[error("eflash_loader image of {0} bytes is shorter than its headers")]
+    LoaderImageTooShort(usize),
     #[error("elf image is not valid")]
     InvalidElf,
     #[error("elf image can not be ran from ram")]
     ElfNotRamLoadable,
     #[error("chip not recognized")]
     UnrecognizedChip,
+    #[error("no quirks known for board {0}")]
+    UnknownBoard(String),
     #[error("flash chip not supported, flash id: {0:#x}")]
     UnsupportedFlash(u8),
+    #[error("flash size unknown, JEDEC capacity byte: {0:#x}")]
+    UnknownFlashSize(u8),
+    #[error("flash io mode {0} not supported by the chip")]
+    UnsupportedFlashMode(u8),
+    #[error("flash clock type {0} not supported by the chip")]
+    UnsupportedFlashClock(u8),
     #[error("ROM error {0:?}")]
     RomError(RomError),
+    /// The device rejected the command, as opposed to `Timeout` where
+    /// it did not answer at all
+    #[error("command {opcode:#04x} failed with {code:?}")]
+    CommandFailed { opcode: u8, code: RomError },
     #[error("Parse error")]
     ParseError(#[from] deku::error::DekuError),
+    /// The device answered, but not with what was expected, usually
+    /// because the framing is out of sync
+    #[error("failed to parse {context} from response {}", hex::encode(.bytes))]
+    ResponseParse {
+        context: &'static str,
+        bytes: Vec<u8>,
+    },
+    #[error("file {0} not found in bundle")]
+    BundleFileMissing(String),
+    #[error("sha256 of bundle file {0} does not match manifest")]
+    BundleChecksumMismatch(String),
+    #[error("segments overlap with different data at {0:#x}")]
+    SegmentOverlap(u32),
+    #[error("partitions {0} and {1} overlap")]
+    PartitionOverlap(String, String),
+    #[error("{len} bytes don't fit partition {name} of {size} bytes")]
+    PartitionTooSmall { name: String, size: u32, len: usize },
+    #[error("invalid boot header: {0}")]
+    InvalidBootHeader(&'static str),
+    #[error("invalid image header magic: {0:#x}")]
+    InvalidImageHeader(u32),
     #[error("Parse toml error")]
     TomlError(#[from] toml::de::Error),
 }
@@ -1,30 +1,251 @@
-use crate::chip::Chip;
+use crate::abort::AbortHandle;
+use crate::chip::{Chip, ChipQuirks, FlashCapabilities, ResetStrategy};
+use crate::defaults;
+use crate::image::ImageHeader;
+use crate::progress::{Progress, ProgressReporter, SharedReporter};
 use crate::Error;
-use crate::{connection::Connection, elf::RomSegment};
-use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use crate::{
+    connection::{Connection, PacketHook},
+    elf::RomSegment,
+};
+use indicatif::HumanBytes;
 use serial::{BaudRate, SerialPort};
 use sha2::{Digest, Sha256};
 use std::{
-    io::{Cursor, Read, Write},
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    ops::{Deref, DerefMut, Range},
+    rc::Rc,
+    sync::{atomic::AtomicBool, Arc},
+    thread::{self, sleep},
+};
+use std::{
+    io::{Cursor, Read, Seek, SeekFrom, Write},
     time::{Duration, Instant},
 };
-use std::{ops::Range, thread::sleep};
 
-fn get_bar(len: u64) -> ProgressBar {
-    let bar = ProgressBar::new(len);
-    bar.set_style(
-        ProgressStyle::default_bar()
-            .template("  {wide_bar} {bytes}/{total_bytes} {bytes_per_sec} {eta}  ")
-            .progress_chars("#>-"),
-    );
-    bar
+/// Flash erase granularity
+const SECTOR_SIZE: u32 = 4096;
+
+/// BP0-BP2, TB and SEC in SR1 of the common SPI NOR flashes
+pub const FLASH_STATUS_PROTECT_MASK: u16 = 0x7c;
+const SPI_READ_STATUS1: u32 = 0x05;
+const SPI_READ_STATUS2: u32 = 0x35;
+const SPI_WRITE_STATUS: u32 = 0x01;
+
+/// Errors after which resending the same packet may succeed
+fn is_transient(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::IO(_)
+            | Error::Serial(_)
+            | Error::Timeout
+            | Error::RespError
+            | Error::ResponseParse { .. }
+    )
+}
+
+fn log_verify(results: &[SegmentVerify]) {
+    for result in results {
+        if result.matched {
+            log::info!("{:x} sha256 match", result.addr);
+        } else {
+            log::warn!(
+                "{:x} sha256 not match: {} != {}",
+                result.addr,
+                hex::encode(result.actual),
+                hex::encode(result.expected)
+            );
+        }
+    }
+}
+
+/// Errors that a reset and reconnect may get past
+fn is_recoverable(e: &Error) -> bool {
+    is_transient(e)
+        || matches!(
+            e,
+            Error::LostSync | Error::LoaderNotRunning | Error::ConnectionFailed(_)
+        )
+}
+
+fn jedec_id(raw: &[u8]) -> u32 {
+    raw.iter().take(3).fold(0, |id, &b| (id << 8) | b as u32)
+}
+
+/// Flash size in bytes from the capacity byte of a JEDEC ID
+fn flash_size(jedec_id: u32) -> Result<u32, Error> {
+    let capacity = jedec_id as u8;
+    // 512KB to 16MB
+    if !(0x13..=0x18).contains(&capacity) {
+        return Err(Error::UnknownFlashSize(capacity));
+    }
+    Ok(1 << capacity)
+}
+
+fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// sha256 of every segment keyed by address, hashed on as many threads as
+/// there are CPUs
+fn local_hashes(segments: &[RomSegment<'_>]) -> HashMap<u32, [u8; 32]> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let per_thread = ((segments.len() + threads - 1) / threads).max(1);
+    thread::scope(|scope| {
+        let handles = segments
+            .chunks(per_thread)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|s| (s.addr, PagedHasher::digest(&s.data)))
+                        .collect::<Vec<(u32, [u8; 32])>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("sha256 thread panicked"))
+            .collect()
+    })
+}
+
+/// A range's digest from the sha256 of each of its pages: the page's own
+/// digest for a single page, otherwise the sha256 of all page digests.
+fn combine_pages(pages: &[[u8; 32]]) -> [u8; 32] {
+    match pages {
+        [page] => *page,
+        pages => Sha256::digest(&pages.concat()).into(),
+    }
+}
+
+/// Local counterpart of [`EflashLoader::sha256_read_paged`], which is the
+/// plain sha256 for data up to [`protocol::MAX_SHA256_READ_LEN`].
+#[derive(Default)]
+struct PagedHasher {
+    page: Sha256,
+    page_len: u32,
+    pages: Vec<[u8; 32]>,
+}
+
+impl PagedHasher {
+    fn digest(data: &[u8]) -> [u8; 32] {
+        let mut hasher = PagedHasher::default();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let len = data
+                .len()
+                .min((protocol::MAX_SHA256_READ_LEN - self.page_len) as usize);
+            self.page.update(&data[..len]);
+            self.page_len += len as u32;
+            data = &data[len..];
+            if self.page_len == protocol::MAX_SHA256_READ_LEN {
+                let page = std::mem::take(&mut self.page);
+                self.pages.push(page.finalize().into());
+                self.page_len = 0;
+            }
+        }
+    }
+
+    fn finalize(mut self) -> [u8; 32] {
+        if self.page_len > 0 || self.pages.is_empty() {
+            self.pages.push(self.page.finalize().into());
+        }
+        combine_pages(&self.pages)
+    }
+}
+
+/// Byte ranges where `a` and `b` differ, including a length difference
+fn mismatched_ranges(a: &[u8], b: &[u8]) -> Vec<Range<usize>> {
+    let len = a.len().max(b.len());
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for i in (0..len).filter(|&i| a.get(i) != b.get(i)) {
+        match ranges.last_mut() {
+            Some(range) if range.end == i => range.end += 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+/// Compare every segment's local hash with the one `read_sha256` returns
+/// for its range, going on past mismatches.
+fn verify_with(
+    segments: &[RomSegment<'_>],
+    hashes: &HashMap<u32, [u8; 32]>,
+    mut read_sha256: impl FnMut(u32, u32) -> Result<[u8; 32], Error>,
+) -> Result<Vec<SegmentVerify>, Error> {
+    let mut results = vec![];
+    for segment in segments {
+        let expected = hashes[&segment.addr];
+        let actual = read_sha256(segment.addr, segment.size())?;
+        results.push(SegmentVerify {
+            addr: segment.addr,
+            size: segment.size(),
+            matched: expected == actual,
+            expected,
+            actual,
+        });
+    }
+    Ok(results)
+}
+
+/// Whether bytes received instead of a handshake reply look like text
+/// printed by a running application.
+fn looks_like_console(data: &[u8]) -> bool {
+    let printable = data
+        .iter()
+        .filter(|&&b| b.is_ascii_graphic() || b.is_ascii_whitespace())
+        .count();
+    data.len() >= 8 && printable * 10 >= data.len() * 9
 }
 
 pub struct Flasher {
     connection: Connection,
     boot_info: protocol::BootInfo,
     chip: Box<dyn Chip>,
+    quirks: ChipQuirks,
+    initial_speed: BaudRate,
     flash_speed: BaudRate,
+    loader_retries: u32,
+    connect_retries: u32,
+    connect_retry_delay: Duration,
+    guard_size: u32,
+    timeouts: TimeoutProfile,
+    chunk_size: usize,
+    pad_final_chunk: Option<u32>,
+    verify: bool,
+    cumulative_progress: bool,
+    abort: AbortHandle,
+    progress_batch: u32,
+    in_session: bool,
+    reporter: Option<SharedReporter>,
+    read_mode: ReadMode,
+    gap_fill: Option<GapFill>,
+    chip_erase: bool,
+    compare_first: bool,
+    verify_retries: u32,
+    strict_segment_header: bool,
+    verify_window: Option<u32>,
+    flash_params: Option<FlashParams>,
+    protected_ranges: Vec<Range<u32>>,
+    allow_protected: bool,
+    upload_window: usize,
+    loader_loaded: bool,
+    auto_unprotect: bool,
+    coalesce: bool,
+    io_retries: u32,
+    io_retry_base: Duration,
+    diagnostics: ConnectDiagnostics,
+    verify_mode: VerifyMode,
 }
 
 impl Flasher {
@@ -34,226 +255,2479 @@ impl Flasher {
         initial_speed: BaudRate,
         flash_speed: BaudRate,
     ) -> Result<Self, Error> {
-        let mut flasher = Flasher {
-            connection: Connection::new(serial),
+        FlasherBuilder::new()
+            .initial_speed(initial_speed)
+            .flash_speed(flash_speed)
+            .connect(chip, serial)
+    }
+
+    /// Connect using board specific quirks instead of the chip's defaults.
+    pub fn connect_with_quirks(
+        chip: impl Chip + 'static,
+        serial: impl SerialPort + 'static,
+        initial_speed: BaudRate,
+        flash_speed: BaudRate,
+        quirks: ChipQuirks,
+    ) -> Result<Self, Error> {
+        FlasherBuilder::new()
+            .initial_speed(initial_speed)
+            .flash_speed(flash_speed)
+            .quirks(quirks)
+            .connect(chip, serial)
+    }
+
+    /// Connect trying each of `candidates` as the initial baud rate in turn,
+    /// returning the first that completes the handshake. An empty slice
+    /// tries 115200, 230400, 460800 and 921600.
+    pub fn connect_autobaud(
+        chip: impl Chip + 'static,
+        serial: impl SerialPort + 'static,
+        candidates: &[BaudRate],
+        flash_speed: BaudRate,
+    ) -> Result<Self, Error> {
+        const DEFAULT_CANDIDATES: &[BaudRate] = &[
+            BaudRate::Baud115200,
+            BaudRate::BaudOther(230400),
+            BaudRate::BaudOther(460800),
+            BaudRate::BaudOther(921600),
+        ];
+        let candidates = if candidates.is_empty() {
+            DEFAULT_CANDIDATES
+        } else {
+            candidates
+        };
+        let quirks = chip.quirks();
+        let mut flasher = Self::new(chip, serial, candidates[0], flash_speed, quirks);
+        for &speed in candidates {
+            log::info!("Trying {} baud", speed.speed());
+            flasher.connection.set_baud(speed)?;
+            match flasher.start_connection() {
+                Ok(()) => {
+                    flasher.initial_speed = speed;
+                    flasher.finish_connect()?;
+                    return Ok(flasher);
+                }
+                Err(e) => log::debug!("{} baud failed: {}", speed.speed(), e),
+            }
+        }
+        Err(Error::AutobaudFailed(
+            candidates.iter().map(|speed| speed.speed()).collect(),
+        ))
+    }
+
+    fn new(
+        chip: impl Chip + 'static,
+        serial: impl SerialPort + 'static,
+        initial_speed: BaudRate,
+        flash_speed: BaudRate,
+        quirks: ChipQuirks,
+    ) -> Self {
+        let mut connection = Connection::new(serial);
+        connection.set_quirks(quirks);
+        Flasher {
+            connection,
             boot_info: protocol::BootInfo::default(),
             chip: Box::new(chip),
+            quirks,
+            initial_speed,
             flash_speed,
-        };
-        flasher.connection.set_baud(initial_speed)?;
-        flasher.start_connection()?;
-        flasher.connection.set_timeout(Duration::from_secs(10))?;
-        flasher.boot_info = flasher.boot_rom().get_boot_info()?;
+            loader_retries: 2,
+            connect_retries: defaults::connect_retries(),
+            connect_retry_delay: Duration::ZERO,
+            guard_size: 0,
+            timeouts: TimeoutProfile::default(),
+            chunk_size: defaults::chunk_size(),
+            pad_final_chunk: None,
+            verify: true,
+            cumulative_progress: false,
+            abort: AbortHandle::default(),
+            progress_batch: 1,
+            in_session: false,
+            reporter: None,
+            read_mode: ReadMode::Single,
+            gap_fill: None,
+            chip_erase: false,
+            compare_first: false,
+            verify_retries: 3,
+            strict_segment_header: false,
+            verify_window: None,
+            flash_params: None,
+            protected_ranges: vec![],
+            allow_protected: false,
+            upload_window: 1,
+            loader_loaded: false,
+            auto_unprotect: false,
+            coalesce: false,
+            io_retries: 3,
+            io_retry_base: Duration::from_millis(50),
+            diagnostics: ConnectDiagnostics::default(),
+            verify_mode: VerifyMode::Sha256,
+        }
+    }
 
-        Ok(flasher)
+    fn finish_connect(&mut self) -> Result<(), Error> {
+        self.connection.set_timeout(self.timeouts.command)?;
+        self.boot_info = self.boot_rom().get_boot_info()?;
+        Ok(())
+    }
+
+    /// After the bootrom didn't answer, check for an eflash_loader left
+    /// running by an earlier run at the flash speed and use it instead.
+    /// Otherwise the port goes back to the initial speed and the bootrom
+    /// error is returned.
+    fn attach_running_loader(&mut self, bootrom_error: Error) -> Result<(), Error> {
+        self.connection.set_baud(self.flash_speed)?;
+        let timeouts = self.timeouts;
+        let result = self.connection.clear_input().and_then(|_| {
+            self.connection
+                .with_timeout(timeouts.handshake, |connection| {
+                    EflashLoader(connection, timeouts).get_boot_info()
+                })
+        });
+        match result {
+            Ok(boot_info) => {
+                log::info!("eflash_loader already running, skipping its upload");
+                self.connection.set_timeout(timeouts.command)?;
+                self.boot_info = boot_info;
+                self.loader_loaded = true;
+                Ok(())
+            }
+            Err(e) => {
+                match e {
+                    Error::RespError
+                    | Error::ResponseParse { .. }
+                    | Error::CommandFailed { .. } => {
+                        log::warn!(
+                            "Something other than the eflash_loader answered at {} baud: {}",
+                            self.flash_speed.speed(),
+                            e
+                        )
+                    }
+                    e => log::debug!("No eflash_loader running: {}", e),
+                }
+                self.connection.set_baud(self.initial_speed)?;
+                Err(bootrom_error)
+            }
+        }
+    }
+
+    /// Pin toggling and timing used to reset the device from now on. Use
+    /// [`Flasher::connect_with_quirks`] to apply it to the connect reset.
+    pub fn set_reset_strategy(&mut self, strategy: ResetStrategy) {
+        self.quirks.reset_strategy = strategy;
+        self.connection.set_reset_strategy(strategy);
+    }
+
+    /// Call `hook` with the raw bytes of every packet sent and received,
+    /// e.g. to diff against a known-good capture.
+    pub fn set_packet_hook(&mut self, hook: Option<PacketHook>) {
+        self.connection.set_packet_hook(hook);
+    }
+
+    /// Number of times to re-enter the eflash_loader from reset when the
+    /// handshake after running it fails. Defaults to 2.
+    pub fn set_loader_retries(&mut self, retries: u32) {
+        self.loader_retries = retries;
+    }
+
+    /// Handshake attempts after resetting into the bootrom before giving
+    /// up, used when reconnecting. Defaults to `BLFLASH_CONNECT_RETRIES`
+    /// or 10.
+    pub fn set_connect_retries(&mut self, retries: u32) {
+        self.connect_retries = retries;
+    }
+
+    /// Extra wait between handshake attempts, for slow booting boards.
+    /// Defaults to none.
+    pub fn set_connect_retry_delay(&mut self, delay: Duration) {
+        self.connect_retry_delay = delay;
+    }
+
+    /// Bytes on either side of each segment to hash before erasing and
+    /// re-check after programming, warning if they were clobbered.
+    /// 0 disables the check, which is the default.
+    pub fn set_guard_size(&mut self, size: u32) {
+        self.guard_size = size;
+    }
+
+    /// Timeout for each command. Defaults to `BLFLASH_TIMEOUT_MS` or 10s.
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.timeouts.command = timeout;
+        self.connection.set_timeout(timeout)
+    }
+
+    /// Timeouts for each kind of operation, see [`TimeoutProfile`].
+    pub fn set_timeout_profile(&mut self, timeouts: TimeoutProfile) -> Result<(), Error> {
+        self.timeouts = timeouts;
+        self.connection.set_timeout(timeouts.command)
+    }
+
+    /// Bytes sent per flash program and eflash_loader data command.
+    /// Defaults to `BLFLASH_CHUNK_SIZE` or 4000.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> Result<(), Error> {
+        if chunk_size == 0 || chunk_size > protocol::MAX_CHUNK_SIZE {
+            return Err(Error::InvalidChunkSize(chunk_size));
+        }
+        self.chunk_size = chunk_size;
+        Ok(())
+    }
+
+    /// Pad a partial final chunk with 0xFF up to a multiple of `align`
+    /// (e.g. the flash page size) for parts that require aligned writes.
+    pub fn set_pad_final_chunk(&mut self, align: Option<u32>) {
+        self.pad_final_chunk = align;
+    }
+
+    /// Compare the sha256 of each written segment afterwards. Defaults to true.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// How written segments are verified, sha256 by default.
+    pub fn set_verify_mode(&mut self, mode: VerifyMode) {
+        self.verify_mode = mode;
+    }
+
+    /// Show one progress bar over all segments to be written instead of
+    /// one per segment. Skip checks then run for every segment up front.
+    pub fn set_cumulative_progress(&mut self, cumulative: bool) {
+        self.cumulative_progress = cumulative;
+    }
+
+    /// Handle to stop flashing or dumping at the next chunk boundary, with
+    /// `Error::Cancelled`.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    /// Use `token` as the flag behind [`Flasher::abort_handle`], so setting
+    /// it stops flashing, dumping or sending the eflash_loader at the next
+    /// chunk boundary.
+    pub fn set_cancel_token(&mut self, token: Arc<AtomicBool>) {
+        self.abort = AbortHandle::from(token);
+    }
+
+    /// Update progress output only every `chunks` chunks while flashing or
+    /// dumping. Defaults to every chunk.
+    pub fn set_progress_batch(&mut self, chunks: u32) {
+        self.progress_batch = chunks;
+    }
+
+    /// SPI mode the flash is switched to before dumping. Falls back to
+    /// single mode if the loader rejects it.
+    pub fn set_read_mode(&mut self, mode: ReadMode) {
+        self.read_mode = mode;
+    }
+
+    /// Fill the gaps between flashed segments instead of leaving their
+    /// previous content. Defaults to leaving them alone.
+    pub fn set_gap_fill(&mut self, gap_fill: Option<GapFill>) {
+        self.gap_fill = gap_fill;
+    }
+
+    /// Times to erase and program a segment again when its sha256 does not
+    /// match afterwards, before failing with `Error::VerifyFailed`.
+    /// Defaults to 3.
+    pub fn set_verify_retries(&mut self, retries: u32) {
+        self.verify_retries = retries;
+    }
+
+    /// Fail with `Error::SegmentHeaderMismatch` when the device echoes a
+    /// different eflash_loader segment header, instead of only warning.
+    pub fn set_strict_segment_header(&mut self, strict: bool) {
+        self.strict_segment_header = strict;
+    }
+
+    /// Also verify every `size` bytes while programming, rounded up to
+    /// whole sectors, and reprogram a window that doesn't match right away.
+    /// Disabled by default, leaving only the verification afterwards.
+    pub fn set_verify_window(&mut self, size: Option<u32>) {
+        self.verify_window =
+            size.map(|size| (size.max(1) + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE);
+    }
+
+    /// Configure the flash with `params` each time the eflash_loader has
+    /// started, which some parts need for reliable programming at high
+    /// baud rates. Not sent by default.
+    pub fn set_flash_params(&mut self, params: FlashParams) {
+        self.flash_params = Some(params);
+    }
+
+    /// When loading segments with `force`, erase the whole chip once
+    /// instead of erasing each segment.
+    pub fn set_chip_erase(&mut self, chip_erase: bool) {
+        self.chip_erase = chip_erase;
+    }
+
+    /// Compare every segment with the flash before erasing any, so all of
+    /// them are read once before the flash is modified. Implied by
+    /// [`Flasher::set_cumulative_progress`].
+    pub fn set_compare_first(&mut self, compare_first: bool) {
+        self.compare_first = compare_first;
+    }
+
+    /// Flash ranges that are refused for erasing and programming, none by
+    /// default. Reads and sha256 verification are always allowed.
+    pub fn set_protected_ranges(&mut self, ranges: Vec<Range<u32>>) {
+        self.protected_ranges = ranges;
+    }
+
+    /// Refuse erasing and programming the chip's
+    /// [`Chip::protected_ranges`], e.g. the boot header. A chip erase is
+    /// refused too while any range is protected.
+    pub fn protect_chip_ranges(&mut self) {
+        self.protected_ranges = self.chip.protected_ranges().to_vec();
+    }
+
+    /// Allow erasing and programming the protected ranges, e.g. to write a
+    /// complete image including its boot header.
+    pub fn set_allow_protected(&mut self, allow: bool) {
+        self.allow_protected = allow;
+    }
+
+    /// Number of eflash_loader data packets sent before waiting for the
+    /// first ack. 1, the default, waits for every ack before sending on.
+    pub fn set_upload_window(&mut self, window: usize) {
+        self.upload_window = window.max(1);
+    }
+
+    /// When an erase is rejected while block protect bits are set in the
+    /// flash status register, clear them and retry the erase once.
+    pub fn set_auto_unprotect(&mut self, auto_unprotect: bool) {
+        self.auto_unprotect = auto_unprotect;
+    }
+
+    /// Merge segments sharing an erase sector before flashing, see
+    /// [`RomSegment::coalesce`]. Off by default. Only `load_segments`
+    /// merges, and its report then lists the merged segments.
+    pub fn set_coalesce(&mut self, coalesce: bool) {
+        self.coalesce = coalesce;
+    }
+
+    /// Resend a program packet up to `retries` times after a transient
+    /// I/O error, waiting `base`, then twice as long before each further
+    /// attempt. Defaults to 3 retries from 50ms.
+    pub fn set_io_retry(&mut self, retries: u32, base: Duration) {
+        self.io_retries = retries;
+        self.io_retry_base = base;
+    }
+
+    /// See [`Connection::set_rate_limit`]
+    pub fn set_rate_limit(&mut self, bytes_per_sec: Option<u32>) {
+        self.connection.set_rate_limit(bytes_per_sec);
+    }
+
+    /// The data rate measured under the rate limit, for the throughput log
+    /// lines
+    fn rate_limit_note(&mut self) -> String {
+        match self.connection.take_throttled_rate() {
+            Some(rate) => format!(", rate limited to {}/s", HumanBytes(rate)),
+            None => String::new(),
+        }
+    }
+
+    /// Report progress to `reporter` instead of drawing indicatif bars.
+    pub fn set_progress_reporter(&mut self, reporter: Box<dyn ProgressReporter>) {
+        self.reporter = Some(SharedReporter(Rc::new(RefCell::new(reporter))));
+    }
+
+    fn progress(&self, len: u64) -> Progress {
+        let progress = match &self.reporter {
+            Some(reporter) => Progress::with_reporter(Box::new(reporter.clone()), len),
+            None => Progress::new(len),
+        };
+        progress.batched(self.progress_batch)
     }
 
     pub fn into_inner(self) -> Connection {
         self.connection
     }
 
+    /// Unlike [`Flasher::into_inner`], which hands back the connection as
+    /// is, flush it, start the application if `reboot` is set, and switch
+    /// back to the initial baud rate so a serial monitor can follow.
+    pub fn finish(mut self, reboot: bool) -> Result<Connection, Error> {
+        self.connection.flush()?;
+        if reboot {
+            if self.loader_loaded {
+                let method = self.run_flashed_app()?;
+                log::debug!("Rebooted by {:?}", method);
+            } else {
+                self.reset()?;
+            }
+        }
+        let initial_speed = self.initial_speed;
+        self.connection.set_baud(initial_speed)?;
+        Ok(self.connection)
+    }
+
+    /// Reset into the application and read its console output at `baud`.
+    /// The baud rate is switched before the reset so no early boot output
+    /// is lost.
+    pub fn into_monitor(self, baud: BaudRate) -> Result<SerialMonitor, Error> {
+        let mut connection = self.into_inner();
+        connection.set_baud(baud)?;
+        connection.reset()?;
+        Ok(SerialMonitor(connection.into_inner()))
+    }
+
+    /// Baud rate the bootrom handshake succeeded at
+    pub fn initial_speed(&self) -> BaudRate {
+        self.initial_speed
+    }
+
+    /// Baud rate used with the eflash_loader
+    pub fn flash_speed(&self) -> BaudRate {
+        self.flash_speed
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Timing of the last bootrom handshake
+    pub fn diagnostics(&self) -> &ConnectDiagnostics {
+        &self.diagnostics
+    }
+
     pub fn boot_info(&self) -> &protocol::BootInfo {
         &self.boot_info
     }
 
+    /// The boot info from connect with its version and security fuses
+    /// decoded.
+    pub fn decoded_boot_info(&self) -> BootInfoDecoded {
+        BootInfoDecoded::from(&self.boot_info)
+    }
+
+    pub fn flash_capabilities(&self) -> Option<FlashCapabilities> {
+        self.chip.flash_capabilities()
+    }
+
+    /// Compare the bootrom version reported at connect with what the
+    /// selected chip expects.
+    pub fn verify_chip_match(&self) -> ChipMatch {
+        let expected = self.chip.bootrom_versions();
+        let detected = self.boot_info.bootrom_version;
+        if expected.is_empty() {
+            log::info!(
+                "No known bootrom versions for {}, skipping chip check (got {})",
+                self.chip.name(),
+                detected
+            );
+        }
+        let chip_match = ChipMatch {
+            expected_chip: self.chip.name(),
+            expected_bootrom_versions: expected,
+            detected_bootrom_version: detected,
+            matched: expected.is_empty() || expected.contains(&detected),
+        };
+        if !chip_match.matched {
+            log::warn!(
+                "Device bootrom version {} does not match {} (expected {:?}), wrong chip selected?",
+                detected,
+                chip_match.expected_chip,
+                expected
+            );
+        }
+        chip_match
+    }
+
+    /// Issue `get_boot_info` `count` times to characterize link stability.
+    ///
+    /// Must be called before the eflash_loader is loaded, as only the
+    /// bootrom answers this command.
+    pub fn boot_info_loop(&mut self, count: u32) -> Result<BootInfoLoopReport, Error> {
+        let mut report = BootInfoLoopReport {
+            attempts: count,
+            succeeded: 0,
+            variants: vec![],
+        };
+        for i in 0..count {
+            match self.boot_rom().get_boot_info() {
+                Ok(boot_info) => {
+                    report.succeeded += 1;
+                    if !report.variants.contains(&boot_info) {
+                        if !report.variants.is_empty() {
+                            log::warn!("Boot info differs at {}: {:x?}", i, boot_info);
+                        }
+                        report.variants.push(boot_info);
+                    }
+                }
+                Err(e) => {
+                    log::debug!("Boot info {} failed: {}", i, e);
+                    self.connection.flush()?;
+                }
+            }
+        }
+        log::info!(
+            "Boot info {}/{} succeeded, {} distinct response(s)",
+            report.succeeded,
+            report.attempts,
+            report.variants.len()
+        );
+        Ok(report)
+    }
+
     pub fn load_segments<'a>(
         &'a mut self,
         force: bool,
         segments: impl Iterator<Item = RomSegment<'a>>,
-    ) -> Result<(), Error> {
-        self.load_eflash_loader()?;
+    ) -> Result<FlashReport, Error> {
+        self.flash_segments(force, segments)
+    }
 
-        for segment in segments {
-            let local_hash = Sha256::digest(&segment.data[0..segment.size() as usize]);
-
-            // skip segment if the contents are matched
-            if !force {
-                let sha256 = self
-                    .eflash_loader()
-                    .sha256_read(segment.addr, segment.size())?;
-                if sha256 == &local_hash[..] {
-                    log::info!(
-                        "Skip segment addr: {:x} size: {} sha256 matches",
-                        segment.addr,
-                        segment.size()
-                    );
-                    continue;
-                }
+    pub(crate) fn flash_segments<'a>(
+        &mut self,
+        force: bool,
+        segments: impl Iterator<Item = RomSegment<'a>>,
+    ) -> Result<FlashReport, Error> {
+        let mut segments = segments.collect::<Vec<_>>();
+        if self.coalesce {
+            let count = segments.len();
+            segments = RomSegment::coalesce(segments, SECTOR_SIZE)?;
+            if segments.len() < count {
+                log::debug!("Coalesced {} segments into {}", count, segments.len());
             }
+        }
+        if let Some(gap_fill) = self.gap_fill {
+            segments = gap_fill.fill(segments);
+        }
+        let hashes = self.ensure_eflash_loader_hashing(&segments)?;
 
-            log::info!(
-                "Erase flash addr: {:x} size: {}",
-                segment.addr,
-                segment.size()
-            );
-            self.eflash_loader()
-                .flash_erase(segment.addr, segment.addr + segment.size())?;
-
-            let mut reader = Cursor::new(&segment.data);
-            let mut cur = segment.addr;
-
-            let start = Instant::now();
-            log::info!("Program flash... {:x}", local_hash);
-            let pb = get_bar(segment.size() as u64);
-            loop {
-                let size = self.eflash_loader().flash_program(cur, &mut reader)?;
-                // log::trace!("program {:x} {:x}", cur, size);
-                cur += size;
-                pb.inc(size as u64);
-                if size == 0 {
-                    break;
+        let start = Instant::now();
+        let mut report = FlashReport::default();
+        // one global erase replaces the per segment erases
+        let erase = !(force && self.chip_erase);
+        if !erase {
+            self.check_protected(0..u32::MAX)?;
+            self.eflash_loader().flash_chip_erase()?;
+        }
+        if self.cumulative_progress || self.compare_first {
+            let mut pending = vec![];
+            for segment in &segments {
+                let local_hash = hashes[&segment.addr];
+                if !force && self.segment_matches(segment, &local_hash)? {
+                    report.segments.push(SegmentStat::skipped(segment));
+                } else {
+                    pending.push((segment, local_hash));
                 }
             }
-            pb.finish_and_clear();
-            let elapsed = start.elapsed();
-            log::info!(
-                "Program done {:?} {}/s",
-                elapsed,
-                HumanBytes((segment.size() as f64 / elapsed.as_millis() as f64 * 1000.0) as u64)
-            );
 
-            let sha256 = self
-                .eflash_loader()
-                .sha256_read(segment.addr, segment.size())?;
-            if sha256 != &local_hash[..] {
-                log::warn!(
-                    "sha256 not match: {} != {}",
-                    hex::encode(sha256),
-                    hex::encode(local_hash)
-                );
+            log::info!("{} of {} segment(s) differ", pending.len(), segments.len());
+
+            let total = pending.iter().map(|(s, _)| s.size() as u64).sum();
+            let mut pb = if self.cumulative_progress {
+                Some(self.progress(total))
+            } else {
+                None
+            };
+            for (segment, local_hash) in pending {
+                let stat = self.write_segment(segment, &local_hash, erase, pb.as_mut())?;
+                report.segments.push(stat);
+            }
+            if let Some(pb) = &mut pb {
+                pb.finish_and_clear();
+            }
+        } else {
+            for segment in segments {
+                let stat = self.load_segment(force, &segment, &hashes[&segment.addr], erase)?;
+                report.segments.push(stat);
             }
         }
-        Ok(())
+        report.elapsed = start.elapsed();
+        Ok(report)
     }
 
-    pub fn check_segments<'a>(
-        &'a mut self,
-        segments: impl Iterator<Item = RomSegment<'a>>,
-    ) -> Result<(), Error> {
-        self.load_eflash_loader()?;
+    /// Flash `size` bytes from `source` at `addr` without holding them in
+    /// memory. Unless `force` is set, `source` is hashed in a first pass
+    /// and the write is skipped if the flash already matches, then it is
+    /// read again from its current position while programming.
+    pub fn flash_stream(
+        &mut self,
+        addr: u32,
+        mut source: impl Read + Seek,
+        size: u32,
+        force: bool,
+    ) -> Result<SegmentStat, Error> {
+        const BLOCK_SIZE: usize = 0x10000;
+        self.check_protected(addr..addr + size)?;
+        self.ensure_eflash_loader()?;
+
+        let origin = source.stream_position()?;
+        if !force {
+            let mut hasher = PagedHasher::default();
+            let mut block = vec![0u8; BLOCK_SIZE];
+            let mut remaining = size as usize;
+            while remaining > 0 {
+                let len = remaining.min(BLOCK_SIZE);
+                source.read_exact(&mut block[..len])?;
+                hasher.update(&block[..len]);
+                remaining -= len;
+            }
+            let local_hash = hasher.finalize();
+            if self.eflash_loader().sha256_read_paged(addr, size)? == local_hash {
+                log::info!("Skip stream addr: {:x} size: {} sha256 matches", addr, size);
+                return Ok(SegmentStat {
+                    addr,
+                    size,
+                    skipped: true,
+                    bytes_written: 0,
+                    attempts: 0,
+                    elapsed: Duration::ZERO,
+                });
+            }
+            source.seek(SeekFrom::Start(origin))?;
+        }
 
-        for segment in segments {
-            let local_hash = Sha256::digest(&segment.data[0..segment.size() as usize]);
+        let start = Instant::now();
+        self.connection.take_throttled_rate();
+        log::info!("Erase flash addr: {:x} size: {}", addr, size);
+        self.erase(addr, addr + size)?;
+
+        let mut hasher = PagedHasher::default();
+        let mut pb = self.progress(size as u64);
+        let chunk_size = self.chunk_size;
+        let mut chunk = vec![0u8; chunk_size];
+        let mut cur = addr;
+        while cur < addr + size {
+            self.abort.check()?;
+            let len = ((addr + size - cur) as usize).min(chunk_size);
+            source.read_exact(&mut chunk[..len])?;
+            hasher.update(&chunk[..len]);
+            let mut reader = Cursor::new(&chunk[..len]);
+            let written = self.program_chunk(cur, &mut reader, len, None)?;
+            cur += written.size;
+            pb.inc(written.size as u64);
+        }
+        self.eflash_loader().flash_program_end()?;
+        pb.finish_and_clear();
+        let stat = SegmentStat {
+            addr,
+            size,
+            skipped: false,
+            bytes_written: size as u64,
+            attempts: 1,
+            elapsed: start.elapsed(),
+        };
+        log::info!(
+            "Program done {:?} {}/s{}",
+            stat.elapsed,
+            HumanBytes(stat.bytes_per_sec()),
+            self.rate_limit_note()
+        );
 
-            let sha256 = self
-                .eflash_loader()
-                .sha256_read(segment.addr, segment.size())?;
-            if sha256 != &local_hash[..] {
-                log::warn!(
-                    "{:x} sha256 not match: {} != {}",
-                    segment.addr,
-                    hex::encode(sha256),
-                    hex::encode(local_hash)
-                );
-            } else {
-                log::info!("{:x} sha256 match", segment.addr);
+        if self.verify {
+            let local_hash = hasher.finalize();
+            let sha256 = self.eflash_loader().sha256_read_paged(addr, size)?;
+            if sha256 != local_hash {
+                return Err(Error::VerifyFailed {
+                    addr,
+                    expected: local_hash,
+                    actual: sha256,
+                });
             }
         }
-        Ok(())
+        Ok(stat)
+    }
+
+    /// Erase the sector at `addr` and write `data` to it, verifying just
+    /// that sector. The rest of the sector is left erased.
+    pub fn flash_write_sector(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        if addr % SECTOR_SIZE != 0 {
+            return Err(Error::SectorUnaligned(addr));
+        }
+        if data.len() > SECTOR_SIZE as usize {
+            return Err(Error::SectorOverflow(data.len()));
+        }
+        self.check_protected(addr..addr + SECTOR_SIZE)?;
+        self.ensure_eflash_loader()?;
+
+        log::info!("Write sector addr: {:x} size: {}", addr, data.len());
+        self.erase(addr, addr + SECTOR_SIZE)?;
+        let mut reader = Cursor::new(data);
+        let mut cur = addr;
+        let chunk_size = self.chunk_size;
+        loop {
+            let chunk = self.program_chunk(cur, &mut reader, chunk_size, None)?;
+            if chunk.size == 0 {
+                break;
+            }
+            cur += chunk.size;
+        }
+        self.eflash_loader().flash_program_end()?;
+
+        let mut sector = data.to_vec();
+        sector.resize(SECTOR_SIZE as usize, 0xFF);
+        self.verify_data(addr, &sector)
+    }
+
+    /// Like [`Flasher::load_segments`], but after a connection error the
+    /// device is reset into the bootrom, the eflash_loader reloaded and
+    /// flashing resumed, up to `max_recoveries` times. Segments already
+    /// written are skipped on the next attempt by their sha256, even with
+    /// `force`.
+    pub fn flash_with_recovery(
+        &mut self,
+        force: bool,
+        segments: &[RomSegment<'_>],
+        max_recoveries: u32,
+    ) -> Result<FlashReport, Error> {
+        let mut recoveries = 0;
+        let mut force = force;
+        loop {
+            let attempt = segments
+                .iter()
+                .map(|s| RomSegment::from_slice(s.addr, &s.data));
+            match self.flash_segments(force, attempt) {
+                Err(e) if is_recoverable(&e) && recoveries < max_recoveries => {
+                    recoveries += 1;
+                    log::warn!(
+                        "Flashing failed: {}, recovering {}/{}",
+                        e,
+                        recoveries,
+                        max_recoveries
+                    );
+                    // what was written before the failure is verified
+                    force = false;
+                    self.reconnect()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Compare each segment with the flash like [`Flasher::load_segments`]
+    /// without erasing or programming anything.
+    pub fn plan_segments<'a>(
+        &mut self,
+        force: bool,
+        segments: impl Iterator<Item = RomSegment<'a>>,
+    ) -> Result<FlashPlan, Error> {
+        self.ensure_eflash_loader()?;
+
+        let mut segments = segments.collect::<Vec<_>>();
+        if let Some(gap_fill) = self.gap_fill {
+            segments = gap_fill.fill(segments);
+        }
+
+        let mut plan = FlashPlan::default();
+        for segment in &segments {
+            let local_hash = PagedHasher::digest(&segment.data);
+            let write = force || !self.segment_matches(segment, &local_hash)?;
+            if write {
+                log::info!(
+                    "Would write segment addr: {:x} size: {}",
+                    segment.addr,
+                    segment.size()
+                );
+            }
+            plan.segments.push(PlannedSegment {
+                addr: segment.addr,
+                size: segment.size(),
+                write,
+            });
+        }
+        Ok(plan)
+    }
+
+    /// Write a raw binary at `addr`, skipping it if the flash already
+    /// matches unless `force` is set.
+    pub fn flash_bin(&mut self, addr: u32, data: &[u8], force: bool) -> Result<FlashReport, Error> {
+        let segment = RomSegment::from_bin(addr, data.into());
+        self.flash_segments(force, std::iter::once(segment))
+    }
+
+    /// Like [`Flasher::load_segments`], but a segment that doesn't match is
+    /// only erased and programmed from where it first diverges, so a flash
+    /// that was interrupted part way through picks up where it stopped.
+    pub fn load_segments_resumable<'a>(
+        &mut self,
+        segments: impl Iterator<Item = RomSegment<'a>>,
+    ) -> Result<(), Error> {
+        self.ensure_eflash_loader()?;
+
+        for segment in segments {
+            let local_hash = PagedHasher::digest(&segment.data);
+            if self.segment_matches(&segment, &local_hash)? {
+                continue;
+            }
+
+            let resume = self.resume_point(&segment)?;
+            if resume > segment.addr {
+                log::info!("Resume segment addr: {:x} at {:x}", segment.addr, resume);
+            }
+            let rest = &segment.data[(resume - segment.addr) as usize..];
+            let rest = RomSegment::from_slice(resume, rest);
+            let rest_hash = PagedHasher::digest(&rest.data);
+            self.write_segment(&rest, &rest_hash, true, None)?;
+        }
+        Ok(())
+    }
+
+    /// Binary search the longest prefix of `segment` already on the device,
+    /// then round its end down to the erase sector containing it.
+    fn resume_point(&mut self, segment: &RomSegment<'_>) -> Result<u32, Error> {
+        // the empty prefix always matches, the full segment never does
+        let (mut good, mut bad) = (0, segment.size());
+        while bad - good > 1 {
+            let len = good + (bad - good) / 2;
+            let local = PagedHasher::digest(&segment.data[..len as usize]);
+            let remote = self.eflash_loader().sha256_read_paged(segment.addr, len)?;
+            if remote == local {
+                good = len;
+            } else {
+                bad = len;
+            }
+        }
+
+        let resume = (segment.addr + good) / SECTOR_SIZE * SECTOR_SIZE;
+        Ok(resume.max(segment.addr))
+    }
+
+    /// Flash the same image to every base offset in `slot_offsets`.
+    ///
+    /// Each segment is written at `slot + segment.addr`, and every slot is
+    /// verified independently unless verification is turned off.
+    pub fn load_segments_to_slots(
+        &mut self,
+        force: bool,
+        segments: &[RomSegment<'_>],
+        slot_offsets: &[u32],
+    ) -> Result<Vec<SlotResult>, Error> {
+        let hashes = self.ensure_eflash_loader_hashing(segments)?;
+
+        let mut results = Vec::with_capacity(slot_offsets.len());
+        for &offset in slot_offsets {
+            log::info!("Flash slot {:x}", offset);
+            // nothing is checked after writing with verification off
+            let mut verified = self.verify;
+            for segment in segments {
+                let local_hash = hashes[&segment.addr];
+                let segment = RomSegment::from_slice(segment.addr + offset, &segment.data);
+                match self.load_segment(force, &segment, &local_hash, true) {
+                    Ok(_) => {}
+                    Err(Error::VerifyFailed { .. } | Error::ReadbackMismatch { .. }) => {
+                        verified = false
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if !verified {
+                log::warn!("Slot {:x} failed verification", offset);
+            }
+            results.push(SlotResult { offset, verified });
+        }
+        Ok(results)
+    }
+
+    /// Returns whether the flash content matches the segment afterwards.
+    fn load_segment(
+        &mut self,
+        force: bool,
+        segment: &RomSegment<'_>,
+        local_hash: &[u8],
+        erase: bool,
+    ) -> Result<SegmentStat, Error> {
+        // skip segment if the contents are matched
+        if !force && self.segment_matches(segment, local_hash)? {
+            return Ok(SegmentStat::skipped(segment));
+        }
+
+        self.write_segment(segment, local_hash, erase, None)
+    }
+
+    fn segment_matches(
+        &mut self,
+        segment: &RomSegment<'_>,
+        local_hash: &[u8],
+    ) -> Result<bool, Error> {
+        let sha256 = self
+            .eflash_loader()
+            .sha256_read_paged(segment.addr, segment.size())?;
+        if sha256 == local_hash {
+            log::info!(
+                "Skip segment addr: {:x} size: {} sha256 matches",
+                segment.addr,
+                segment.size()
+            );
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Program one chunk, resending it after transient I/O errors.
+    fn program_chunk(
+        &mut self,
+        addr: u32,
+        reader: &mut Cursor<impl AsRef<[u8]>>,
+        chunk_size: usize,
+        pad_to: Option<u32>,
+    ) -> Result<ProgramChunk, Error> {
+        let position = reader.position();
+        let mut attempt = 0;
+        loop {
+            match self
+                .eflash_loader()
+                .flash_program(addr, reader, chunk_size, pad_to)
+            {
+                Err(e) if is_transient(&e) && attempt < self.io_retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "Program addr: {:x} failed: {}, retry {}/{}",
+                        addr,
+                        e,
+                        attempt,
+                        self.io_retries
+                    );
+                    sleep(self.io_retry_base * 2u32.pow(attempt - 1));
+                    self.connection.clear_input()?;
+                    reader.set_position(position);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn check_protected(&self, range: Range<u32>) -> Result<(), Error> {
+        if self.allow_protected {
+            return Ok(());
+        }
+        match self
+            .protected_ranges
+            .iter()
+            .find(|p| p.start < range.end && range.start < p.end)
+        {
+            Some(p) => Err(Error::ProtectedRegion {
+                addr: p.start.max(range.start),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Erase, program and verify a segment, driving `pb` if given or a
+    /// progress bar of its own otherwise.
+    fn write_segment(
+        &mut self,
+        segment: &RomSegment<'_>,
+        local_hash: &[u8],
+        erase: bool,
+        mut pb: Option<&mut Progress>,
+    ) -> Result<SegmentStat, Error> {
+        self.check_protected(segment.addr..segment.addr + segment.size())?;
+        let start = Instant::now();
+        let mut attempts = 1;
+        loop {
+            // a retry always needs its own erase
+            let erase = erase || attempts > 1;
+            self.connection.take_throttled_rate();
+            self.program_segment(segment, local_hash, erase, pb.as_deref_mut())?;
+            let stat = SegmentStat {
+                addr: segment.addr,
+                size: segment.size(),
+                skipped: false,
+                bytes_written: segment.size() as u64 * attempts as u64,
+                attempts,
+                elapsed: start.elapsed(),
+            };
+            log::info!(
+                "Program done {:?} {}/s{}",
+                stat.elapsed,
+                HumanBytes(stat.bytes_per_sec()),
+                self.rate_limit_note()
+            );
+            if !self.verify {
+                return Ok(stat);
+            }
+            match self.verify_data(segment.addr, &segment.data) {
+                Ok(()) => return Ok(stat),
+                Err(Error::VerifyFailed { .. } | Error::ReadbackMismatch { .. })
+                    if attempts <= self.verify_retries =>
+                {
+                    log::warn!(
+                        "Retry segment addr: {:x} {}/{}",
+                        segment.addr,
+                        attempts,
+                        self.verify_retries
+                    );
+                    attempts += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn program_segment(
+        &mut self,
+        segment: &RomSegment<'_>,
+        local_hash: &[u8],
+        erase: bool,
+        pb: Option<&mut Progress>,
+    ) -> Result<(), Error> {
+        let mut guards = vec![];
+        if erase {
+            guards = self.read_guards(segment)?;
+
+            log::info!(
+                "Erase flash addr: {:x} size: {}",
+                segment.addr,
+                segment.size()
+            );
+            self.erase(segment.addr, segment.addr + segment.size())?;
+        }
+
+        let mut reader = Cursor::new(&segment.data);
+        let mut cur = segment.addr;
+
+        log::info!("Program flash... {}", hex::encode(local_hash));
+        let mut own_pb = None;
+        let pb = match pb {
+            Some(pb) => pb,
+            None => own_pb.insert(self.progress(segment.size() as u64)),
+        };
+        let chunk_size = self.chunk_size;
+        let pad_to = self.pad_final_chunk;
+        let mut window_start = segment.addr;
+        let mut window_retries = 0;
+        loop {
+            self.abort.check()?;
+            let chunk = self.program_chunk(cur, &mut reader, chunk_size, pad_to)?;
+            // log::trace!("program {:x} {:x}", cur, chunk.size);
+            if chunk.partial {
+                log::debug!(
+                    "Final chunk at {:x} partial: {} bytes, padded {}",
+                    cur,
+                    chunk.size,
+                    chunk.padding
+                );
+            }
+            cur += chunk.size;
+            pb.inc(chunk.size as u64);
+
+            if let Some(window) = self.verify_window {
+                let done = chunk.size == 0;
+                // windows end on a sector so a bad one can be erased alone
+                let window_end = if done {
+                    cur
+                } else {
+                    cur / SECTOR_SIZE * SECTOR_SIZE
+                };
+                if window_end > window_start && (done || window_end - window_start >= window) {
+                    let offset = (window_start - segment.addr) as usize;
+                    let data = &segment.data[offset..(window_end - segment.addr) as usize];
+                    let local = PagedHasher::digest(data);
+                    let remote = self
+                        .eflash_loader()
+                        .sha256_read_paged(window_start, data.len() as u32)?;
+                    if remote == local {
+                        window_start = window_end;
+                        window_retries = 0;
+                    } else if window_start % SECTOR_SIZE == 0
+                        && window_retries < self.verify_retries
+                    {
+                        window_retries += 1;
+                        log::warn!(
+                            "Reprogram window {:x}..{:x} {}/{}",
+                            window_start,
+                            window_end,
+                            window_retries,
+                            self.verify_retries
+                        );
+                        self.erase(window_start, cur)?;
+                        reader.set_position(offset as u64);
+                        cur = window_start;
+                        continue;
+                    } else {
+                        return Err(Error::VerifyFailed {
+                            addr: window_start,
+                            expected: local.into(),
+                            actual: remote,
+                        });
+                    }
+                }
+            }
+
+            if chunk.size == 0 {
+                break;
+            }
+        }
+        self.eflash_loader().flash_program_end()?;
+        if let Some(pb) = &mut own_pb {
+            pb.finish_and_clear();
+        }
+
+        self.check_guards(&guards)
+    }
+
+    /// Compare the flash at `addr` with `data` by sha256 of each block,
+    /// showing progress by bytes hashed.
+    fn verify_data(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        const VERIFY_BLOCK_SIZE: usize = 0x10000;
+
+        if self.verify_mode == VerifyMode::Readback {
+            return self.verify_readback(addr, data);
+        }
+
+        log::info!("Verify flash addr: {:x} size: {}", addr, data.len());
+        let mut pb = if self.cumulative_progress {
+            Progress::hidden()
+        } else {
+            self.progress(data.len() as u64)
+        };
+        for (i, block) in data.chunks(VERIFY_BLOCK_SIZE).enumerate() {
+            self.abort.check()?;
+            let block_addr = addr + (i * VERIFY_BLOCK_SIZE) as u32;
+            let sha256 = self
+                .eflash_loader()
+                .sha256_read(block_addr, block.len() as u32)?;
+            let local_hash = Sha256::digest(block);
+            pb.inc(block.len() as u64);
+            if sha256 != local_hash[..] {
+                pb.finish_and_clear();
+                log::warn!(
+                    "{:x} sha256 not match: {} != {}",
+                    block_addr,
+                    hex::encode(sha256),
+                    hex::encode(local_hash)
+                );
+                return Err(Error::VerifyFailed {
+                    addr: block_addr,
+                    expected: local_hash.into(),
+                    actual: sha256,
+                });
+            }
+        }
+        pb.finish_and_clear();
+        Ok(())
+    }
+
+    /// Read `data`'s range back and compare it byte by byte, failing with
+    /// the offset of the first difference.
+    fn verify_readback(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        const BLOCK_SIZE: usize = 4096;
+
+        log::info!("Read back flash addr: {:x} size: {}", addr, data.len());
+        let mut pb = if self.cumulative_progress {
+            Progress::hidden()
+        } else {
+            self.progress(data.len() as u64)
+        };
+        for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+            self.abort.check()?;
+            let offset = i * BLOCK_SIZE;
+            let remote = self
+                .eflash_loader()
+                .flash_read(addr + offset as u32, block.len() as u32)?;
+            pb.inc(block.len() as u64);
+            if let Some(pos) = block.iter().zip(&remote).position(|(a, b)| a != b) {
+                pb.finish_and_clear();
+                let offset = (offset + pos) as u32;
+                log::warn!(
+                    "{:x} read back {:02x}, expected {:02x}",
+                    addr + offset,
+                    remote[pos],
+                    block[pos]
+                );
+                return Err(Error::ReadbackMismatch { addr, offset });
+            }
+        }
+        pb.finish_and_clear();
+        Ok(())
+    }
+
+    fn read_guards(&mut self, segment: &RomSegment<'_>) -> Result<Vec<Guard>, Error> {
+        if self.guard_size == 0 {
+            return Ok(vec![]);
+        }
+        let before = segment.addr.saturating_sub(self.guard_size);
+        let end = segment.addr + segment.size();
+        let ranges = [before..segment.addr, end..end + self.guard_size];
+
+        let mut guards = vec![];
+        for range in ranges.iter().filter(|r| !r.is_empty()) {
+            let sha256 = self
+                .eflash_loader()
+                .sha256_read(range.start, range.len() as u32)?;
+            guards.push(Guard {
+                range: range.clone(),
+                sha256,
+            });
+        }
+        Ok(guards)
+    }
+
+    fn check_guards(&mut self, guards: &[Guard]) -> Result<(), Error> {
+        for guard in guards {
+            let sha256 = self
+                .eflash_loader()
+                .sha256_read(guard.range.start, guard.range.len() as u32)?;
+            if sha256 != guard.sha256 {
+                log::warn!(
+                    "Flash outside segment changed: {:x}..{:x}",
+                    guard.range.start,
+                    guard.range.end
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_segments<'a>(
+        &'a mut self,
+        segments: impl Iterator<Item = RomSegment<'a>>,
+    ) -> Result<(), Error> {
+        let results = self.verify_segments(segments)?;
+        log_verify(&results);
+        Ok(())
+    }
+
+    /// Like [`Flasher::check_segments`], but without loading the
+    /// eflash_loader, for when it was left running by an earlier process,
+    /// e.g. with a flasher from [`FlasherBuilder::attach`]. Fails with
+    /// `Error::LoaderNotRunning` if it doesn't answer the first hash
+    /// request.
+    pub fn check_segments_assume_loader<'a>(
+        &mut self,
+        segments: impl Iterator<Item = RomSegment<'a>>,
+    ) -> Result<(), Error> {
+        let segments = segments.collect::<Vec<_>>();
+        let hashes = local_hashes(&segments);
+        let results = match self.verify_hashed(&segments, &hashes) {
+            Err(e) if is_transient(&e) => {
+                log::debug!("eflash_loader didn't answer: {}", e);
+                return Err(Error::LoaderNotRunning);
+            }
+            result => result?,
+        };
+        self.loader_loaded = true;
+        log_verify(&results);
+        Ok(())
+    }
+
+    /// Compare the sha256 of every segment with the flash. A mismatch
+    /// doesn't stop the remaining segments from being checked.
+    pub fn verify_segments<'a>(
+        &mut self,
+        segments: impl Iterator<Item = RomSegment<'a>>,
+    ) -> Result<Vec<SegmentVerify>, Error> {
+        let segments = segments.collect::<Vec<_>>();
+        let hashes = self.ensure_eflash_loader_hashing(&segments)?;
+        self.verify_hashed(&segments, &hashes)
+    }
+
+    /// Whether the flash at `addr` holds exactly `data`, see
+    /// [`Flasher::compare`] for the digests.
+    pub fn matches(&mut self, addr: u32, data: &[u8]) -> Result<bool, Error> {
+        Ok(self.compare(addr, data)?.matched)
+    }
+
+    /// Compare the sha256 of `data` with the flash at `addr`, loading the
+    /// eflash_loader only if it isn't running yet.
+    pub fn compare(&mut self, addr: u32, data: &[u8]) -> Result<SegmentVerify, Error> {
+        self.ensure_eflash_loader()?;
+        let expected = PagedHasher::digest(data);
+        let actual = self
+            .eflash_loader()
+            .sha256_read_paged(addr, data.len() as u32)?;
+        Ok(SegmentVerify {
+            addr,
+            size: data.len() as u32,
+            matched: expected == actual,
+            expected,
+            actual,
+        })
+    }
+
+    fn verify_hashed(
+        &mut self,
+        segments: &[RomSegment<'_>],
+        hashes: &HashMap<u32, [u8; 32]>,
+    ) -> Result<Vec<SegmentVerify>, Error> {
+        verify_with(segments, hashes, |addr, len| {
+            self.abort.check()?;
+            self.eflash_loader().sha256_read_paged(addr, len)
+        })
+    }
+
+    pub fn dump_flash(&mut self, range: Range<u32>, writer: impl Write) -> Result<(), Error> {
+        let start = range.start;
+        self.dump_flash_resumable(range, start, writer, |_| {})
+    }
+
+    /// Dump `range` from `start` on, calling `on_block` with the address up
+    /// to which data was written after each block. Passing the last
+    /// reported address as `start` resumes an interrupted dump.
+    pub fn dump_flash_resumable(
+        &mut self,
+        range: Range<u32>,
+        start: u32,
+        mut writer: impl Write,
+        mut on_block: impl FnMut(u32),
+    ) -> Result<(), Error> {
+        self.ensure_eflash_loader()?;
+
+        let read_mode = self.read_mode;
+        if read_mode != ReadMode::Single {
+            match self.eflash_loader().flash_set_io_mode(read_mode as u8) {
+                Ok(()) => log::info!("Reading flash in {:?} mode", read_mode),
+                Err(e) => {
+                    log::warn!(
+                        "{:?} read mode not supported, using single: {}",
+                        read_mode,
+                        e
+                    );
+                    self.read_mode = ReadMode::Single;
+                }
+            }
+        }
+
+        const BLOCK_SIZE: usize = 4096;
+        let mut cur = start.clamp(range.start, range.end);
+        let resumed_at = cur;
+        let start = Instant::now();
+        let mut pb = self.progress(range.len() as u64);
+        pb.inc((cur - range.start) as u64);
+        while cur < range.end {
+            self.abort.check()?;
+            let data = self
+                .eflash_loader()
+                .flash_read(cur, (range.end - cur).min(BLOCK_SIZE as u32))?;
+            writer.write_all(&data)?;
+            cur += data.len() as u32;
+            pb.inc(data.len() as u64);
+            on_block(cur);
+        }
+        pb.finish_and_clear();
+        let elapsed = start.elapsed();
+        let len = cur - resumed_at;
+        log::info!(
+            "Read {} in {:?} {}/s",
+            HumanBytes(len as u64),
+            elapsed,
+            HumanBytes((len as f64 / elapsed.as_millis().max(1) as f64 * 1000.0) as u64)
+        );
+
+        Ok(())
+    }
+
+    /// Dump `range` without the trailing run of 0xFF that extends to the end.
+    /// Returns the used length written to `writer`.
+    pub fn dump_flash_trim(&mut self, range: Range<u32>, writer: impl Write) -> Result<u32, Error> {
+        let mut writer = TrimWriter {
+            inner: writer,
+            pending: 0,
+            written: 0,
+        };
+        self.dump_flash(range, &mut writer)?;
+        log::info!(
+            "Used length {:x}, trimmed {} bytes of 0xFF",
+            writer.written,
+            writer.pending
+        );
+        Ok(writer.written as u32)
+    }
+
+    /// Dump `range` leaving out blocks that are entirely 0xFF. Only the
+    /// data of the returned extents is written, back to back.
+    pub fn dump_flash_sparse(
+        &mut self,
+        range: Range<u32>,
+        writer: impl Write,
+    ) -> Result<Vec<Range<u32>>, Error> {
+        let mut writer = SparseWriter {
+            inner: writer,
+            pos: range.start,
+            extents: vec![],
+        };
+        self.dump_flash(range, &mut writer)?;
+        log::info!("{} non-empty extent(s)", writer.extents.len());
+        Ok(writer.extents)
+    }
+
+    /// Read the JEDEC manufacturer/device ID of the fitted flash as
+    /// `0xMMTTCC` (manufacturer, memory type, capacity).
+    pub fn read_flash_id(&mut self) -> Result<u32, Error> {
+        Ok(jedec_id(&self.read_flash_id_raw()?))
+    }
+
+    /// Flash size in bytes from the capacity byte of the JEDEC ID, which is
+    /// log2 of the size for most vendors.
+    pub fn detect_flash_size(&mut self) -> Result<u32, Error> {
+        flash_size(self.read_flash_id()?)
+    }
+
+    /// The raw JEDEC ID response, for matching against a lookup table.
+    pub fn read_flash_id_raw(&mut self) -> Result<Vec<u8>, Error> {
+        self.ensure_eflash_loader()?;
+        self.eflash_loader().flash_read_jedec_id()
+    }
+
+    /// Read `len` bytes of eFuse starting `addr` bytes into the eFuse region.
+    pub fn efuse_read(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, Error> {
+        if addr
+            .checked_add(len)
+            .map_or(true, |end| end > self.chip.efuse_size())
+        {
+            return Err(Error::InvalidEfuseRange { addr, len });
+        }
+        self.ensure_eflash_loader()?;
+        self.eflash_loader().efuse_read(addr, len)
+    }
+
+    /// Write the chip's whole eFuse region to `writer`.
+    pub fn dump_efuse(&mut self, mut writer: impl Write) -> Result<(), Error> {
+        const BLOCK_SIZE: u32 = 32;
+        self.ensure_eflash_loader()?;
+
+        let size = self.chip.efuse_size();
+        let mut pb = self.progress(size as u64);
+        let mut cur = 0;
+        while cur < size {
+            self.abort.check()?;
+            let len = (size - cur).min(BLOCK_SIZE);
+            let data = self.eflash_loader().efuse_read(cur, len)?;
+            if data.len() != len as usize {
+                pb.finish_and_clear();
+                return Err(Error::ShortRead {
+                    requested: len,
+                    got: data.len() as u32,
+                });
+            }
+            writer.write_all(&data)?;
+            cur += len;
+            pb.inc(len as u64);
+        }
+        pb.finish_and_clear();
+        log::info!("Read {} bytes of eFuse", size);
+
+        Ok(())
+    }
+
+    /// Read the factory MAC address from eFuse.
+    pub fn read_mac(&mut self) -> Result<[u8; 6], Error> {
+        self.ensure_eflash_loader()?;
+        self.eflash_loader().read_mac()
+    }
+
+    /// Read a 32-bit word of device memory, e.g. a peripheral register.
+    pub fn read_reg(&mut self, addr: u32) -> Result<u32, Error> {
+        if addr % 4 != 0 {
+            return Err(Error::Unaligned(addr));
+        }
+        self.ensure_eflash_loader()?;
+        self.eflash_loader().mem_read(addr)
+    }
+
+    /// Read the flash status registers, SR1 in the low byte and SR2 in the
+    /// high byte. The block protect bits are [`FLASH_STATUS_PROTECT_MASK`].
+    pub fn read_flash_status(&mut self) -> Result<u16, Error> {
+        self.ensure_eflash_loader()?;
+        let status = self.eflash_loader().read_flash_status()?;
+        log::debug!(
+            "Flash status: {:04x}, block protect bits: {:02x}",
+            status,
+            status & FLASH_STATUS_PROTECT_MASK
+        );
+        Ok(status)
+    }
+
+    /// Write SR1 from the low byte and SR2 from the high byte of `value`.
+    pub fn write_flash_status(&mut self, value: u16) -> Result<(), Error> {
+        self.ensure_eflash_loader()?;
+        self.eflash_loader().write_flash_status(value)
+    }
+
+    fn erase(&mut self, start: u32, end: u32) -> Result<(), Error> {
+        match self.eflash_loader().flash_erase(start, end) {
+            Err(e @ Error::CommandFailed { .. }) if self.auto_unprotect => {
+                let status = self.eflash_loader().read_flash_status()?;
+                if status & FLASH_STATUS_PROTECT_MASK == 0 {
+                    return Err(e);
+                }
+                log::warn!(
+                    "Erase failed with flash status {:04x}, clearing block protection",
+                    status
+                );
+                self.eflash_loader()
+                    .write_flash_status(status & !FLASH_STATUS_PROTECT_MASK)?;
+                self.eflash_loader().flash_erase(start, end)
+            }
+            result => result,
+        }
+    }
+
+    /// Send an arbitrary command to whichever of the bootrom or the
+    /// eflash_loader is running, returning the raw response bytes. Meant
+    /// for probing undocumented opcodes, the typed methods are preferred.
+    /// Opcodes are one byte on the wire, hence `u8`, like `Command::CMD_ID`.
+    pub fn send_command(
+        &mut self,
+        opcode: u8,
+        payload: &[u8],
+        expected_resp_len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        self.connection
+            .send_command(opcode, payload, expected_resp_len)
+    }
+
+    /// Write a 32-bit word of device memory, e.g. a peripheral register.
+    pub fn write_reg(&mut self, addr: u32, value: u32) -> Result<(), Error> {
+        if addr % 4 != 0 {
+            return Err(Error::Unaligned(addr));
+        }
+        self.ensure_eflash_loader()?;
+        self.eflash_loader().mem_write(addr, value)
+    }
+
+    /// Erase the whole flash.
+    pub fn flash_chip_erase(&mut self) -> Result<(), Error> {
+        self.ensure_eflash_loader()?;
+        self.eflash_loader().flash_chip_erase()
+    }
+
+    /// Read and decode the boot header of the image flashed at `slot_addr`.
+    pub fn read_image_header(&mut self, slot_addr: u32) -> Result<ImageHeader, Error> {
+        self.ensure_eflash_loader()?;
+
+        let data = self
+            .eflash_loader()
+            .flash_read(slot_addr, protocol::LOAD_BOOT_HEADER_LEN as u32)?;
+        ImageHeader::parse(&data)
+    }
+
+    /// Load the eflash_loader once for several operations. Operations on
+    /// the returned session don't reload it, and the device is reset when
+    /// the session is dropped.
+    pub fn session(&mut self) -> Result<FlasherSession<'_>, Error> {
+        self.ensure_eflash_loader()?;
+        self.in_session = true;
+        Ok(FlasherSession { flasher: self })
+    }
+
+    /// Load the eflash_loader while the sha256 of each segment is computed
+    /// on other threads, returning the hashes keyed by address.
+    fn ensure_eflash_loader_hashing(
+        &mut self,
+        segments: &[RomSegment<'_>],
+    ) -> Result<HashMap<u32, [u8; 32]>, Error> {
+        thread::scope(|scope| {
+            let hashes = scope.spawn(|| local_hashes(segments));
+            self.ensure_eflash_loader()?;
+            Ok(hashes.join().expect("sha256 thread panicked"))
+        })
+    }
+
+    /// Load the eflash_loader unless it's already running and answers.
+    pub fn ensure_eflash_loader(&mut self) -> Result<(), Error> {
+        if self.in_session {
+            return Ok(());
+        }
+        if self.loader_loaded {
+            if self.is_eflash_loader_running() {
+                log::debug!("eflash_loader already running");
+                return Ok(());
+            }
+            log::info!("eflash_loader stopped answering, reconnecting");
+            self.reconnect()?;
+        }
+        self.load_eflash_loader()
+    }
+
+    /// Whether the eflash_loader was loaded and still answers a flash ID
+    /// read.
+    pub fn is_eflash_loader_running(&mut self) -> bool {
+        if !self.loader_loaded {
+            return false;
+        }
+        let timeouts = self.timeouts;
+        self.connection
+            .with_timeout(timeouts.handshake, |connection| {
+                EflashLoader(connection, timeouts).flash_read_jedec_id()
+            })
+            .is_ok()
+    }
+
+    /// Reconnect to the bootrom and load the eflash_loader again, for when
+    /// the device was reset behind the flasher's back.
+    pub fn force_reload_loader(&mut self) -> Result<(), Error> {
+        self.reconnect()?;
+        self.load_eflash_loader()
+    }
+
+    fn reconnect(&mut self) -> Result<(), Error> {
+        self.loader_loaded = false;
+        self.connection.set_baud(self.initial_speed)?;
+        self.start_connection()
+    }
+
+    /// Send the chip's eflash_loader and switch to it. The image is checked
+    /// against the chip's known digest before sending, then as in
+    /// [`Flasher::load_eflash_loader_from`].
+    pub fn load_eflash_loader(&mut self) -> Result<(), Error> {
+        let image = self.chip.get_eflash_loader().to_vec();
+        if let Some(expected) = self.chip.eflash_loader_sha256() {
+            let actual: [u8; 32] = Sha256::digest(&image).into();
+            if actual != expected {
+                return Err(Error::LoaderVerifyFailed { expected, actual });
+            }
+        }
+        self.load_eflash_loader_from(&image)
+    }
+
+    /// Load `image` in place of the chip's built-in eflash_loader, e.g. a
+    /// patched one. Its data must match the sha256 in its boot header, and
+    /// after the upload the bootrom checks what it received against that
+    /// hash, failing with `Error::LoaderRejected`.
+    pub fn load_eflash_loader_from(&mut self, image: &[u8]) -> Result<(), Error> {
+        const HEADERS_LEN: usize =
+            protocol::LOAD_BOOT_HEADER_LEN + protocol::LOAD_SEGMENT_HEADER_LEN;
+        if image.len() < HEADERS_LEN {
+            return Err(Error::LoaderImageTooShort(image.len()));
+        }
+        // the bootrom's check_image only compares the data it received with
+        // the header, so make sure the header describes the image we send
+        let header = ImageHeader::parse(&image[..protocol::LOAD_BOOT_HEADER_LEN])?;
+        let actual: [u8; 32] = Sha256::digest(&image[protocol::LOAD_BOOT_HEADER_LEN..]).into();
+        if actual != header.sha256 {
+            return Err(Error::LoaderVerifyFailed {
+                expected: header.sha256,
+                actual,
+            });
+        }
+
+        self.loader_loaded = false;
+        let mut retry = 0;
+        loop {
+            self.send_eflash_loader(image)?;
+            match self.enter_eflash_loader() {
+                Ok(()) => break,
+                Err(e) if retry < self.loader_retries => {
+                    retry += 1;
+                    log::warn!(
+                        "Handshake with eflash_loader failed: {}, retry {}/{}",
+                        e,
+                        retry,
+                        self.loader_retries
+                    );
+                    self.connection.set_baud(self.initial_speed)?;
+                    self.start_connection()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        log::info!("Entered eflash_loader");
+        self.loader_loaded = true;
+
+        if let Some(params) = self.flash_params {
+            log::debug!("Set flash params: {:?}", params);
+            self.eflash_loader().flash_set_para(params)?;
+        }
+
+        match self.eflash_loader().flash_read_jedec_id() {
+            Ok(raw) => log::info!("Flash JEDEC ID: {:06x}", jedec_id(&raw)),
+            Err(e) => log::debug!("Failed to read flash JEDEC ID: {}", e),
+        }
+        match self.eflash_loader().read_mac() {
+            Ok(mac) => log::debug!("MAC address: {}", format_mac(&mac)),
+            Err(e) => log::debug!("Failed to read MAC address: {}", e),
+        }
+
+        Ok(())
+    }
+
+    fn send_eflash_loader(&mut self, image: &[u8]) -> Result<(), Error> {
+        let len = image.len();
+        let mut reader = Cursor::new(image);
+        self.boot_rom().load_boot_header(&mut reader)?;
+        let strict = self.strict_segment_header;
+        self.boot_rom().load_segment_header(&mut reader, strict)?;
+
+        let start = Instant::now();
+        self.connection.take_throttled_rate();
+        log::info!("Sending eflash_loader...");
+        let mut pb = self.progress(len as u64);
+        let chunk_size = self.chunk_size;
+        let window = self.upload_window;
+        if window > 1 {
+            let abort = self.abort.clone();
+            self.boot_rom().load_segment_data_pipelined(
+                &mut reader,
+                chunk_size,
+                window,
+                |size| {
+                    pb.inc(size as u64);
+                    abort.check()
+                },
+            )?;
+        } else {
+            loop {
+                self.abort.check()?;
+                let size = self.boot_rom().load_segment_data(&mut reader, chunk_size)?;
+                pb.inc(size as u64);
+                if size == 0 {
+                    break;
+                }
+            }
+        }
+        pb.finish_and_clear();
+        let elapsed = start.elapsed();
+        log::info!(
+            "Finished {:?} {}/s, {} packet(s) in flight{}",
+            elapsed,
+            HumanBytes((len as f64 / elapsed.as_millis().max(1) as f64 * 1000.0) as u64),
+            window.max(1),
+            self.rate_limit_note()
+        );
+
+        let start = self.chip.loader_start();
+        match self.boot_rom().check_image_with(start.check_image) {
+            Err(Error::CommandFailed { code, .. }) => return Err(Error::LoaderRejected(code)),
+            result => result?,
+        }
+        self.boot_rom().run_image_with(start.run_image)?;
+
+        Ok(())
+    }
+
+    fn enter_eflash_loader(&mut self) -> Result<(), Error> {
+        sleep(self.quirks.loader_start_delay);
+        let stale = self.connection.set_baud_and_drain(self.flash_speed)?;
+        if stale > 0 {
+            log::debug!("Discarded {} bytes after the baud switch", stale);
+        }
+        self.handshake().map_err(|(e, _)| e)
+    }
+
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.loader_loaded = false;
+        self.connection.reset()
+    }
+
+    /// Start the flashed application, asking the eflash_loader to reboot
+    /// and toggling the reset line if it rejects the command.
+    pub fn run_flashed_app(&mut self) -> Result<RebootMethod, Error> {
+        self.loader_loaded = false;
+        match self.eflash_loader().reboot() {
+            Ok(()) => Ok(RebootMethod::Command),
+            Err(e @ Error::CommandFailed { .. }) => {
+                log::debug!("Reboot command failed: {}, resetting", e);
+                self.reset()?;
+                Ok(RebootMethod::Reset)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn boot_rom(&mut self) -> BootRom {
+        BootRom(&mut self.connection)
+    }
+
+    fn eflash_loader(&mut self) -> EflashLoader {
+        EflashLoader(&mut self.connection, self.timeouts)
+    }
+
+    /// Discard whatever the device sent that wasn't read yet, e.g. to
+    /// resync after a failed operation without reconnecting. Returns the
+    /// number of bytes dropped.
+    pub fn clear_input(&mut self) -> Result<usize, Error> {
+        self.connection.clear_input()
+    }
+
+    /// Send the 0x55 burst and poll for the answer until
+    /// `timeouts.handshake` has passed since the burst was sent. On failure
+    /// the bytes received instead of the answer are returned with the error.
+    fn handshake(&mut self) -> Result<(), (Error, Vec<u8>)> {
+        let mut received = vec![];
+        self.send_handshake(&mut received)
+            .map_err(|e| (e, received))
+    }
+
+    fn send_handshake(&mut self, received: &mut Vec<u8>) -> Result<(), Error> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(10);
+        const MAX_KEPT: usize = 1024;
+
+        let len = self
+            .connection
+            .calc_duration_length(Duration::from_millis(5));
+        log::trace!("5ms send count {}", len);
+        let data: Vec<u8> = std::iter::repeat(0x55u8).take(len).collect();
+        let start = Instant::now();
+        self.connection.write_all(&data)?;
+        self.connection.flush()?;
+        let sent = Instant::now();
+        log::trace!("handshake sent elapsed {:?}", sent - start);
+
+        loop {
+            let pending = self.connection.read_pending(POLL_INTERVAL, 256)?;
+            let keep = pending.len().min(MAX_KEPT.saturating_sub(received.len()));
+            received.extend_from_slice(&pending[..keep]);
+            if pending.windows(2).any(|w| w == b"OK") {
+                log::trace!("handshake answered after {:?}", sent.elapsed());
+                return Ok(());
+            }
+            if sent.elapsed() >= self.timeouts.handshake {
+                break;
+            }
+        }
+
+        Err(if received.is_empty() {
+            Error::Timeout
+        } else {
+            Error::LostSync
+        })
+    }
+
+    fn start_connection(&mut self) -> Result<(), Error> {
+        log::info!("Start connection...");
+        // stale bytes from an earlier session, before the device can answer
+        self.connection.clear_input()?;
+        match self.connection.reset_to_flash() {
+            Err(Error::ResetUnavailable) => {
+                log::info!("No reset lines, expecting the device in the bootloader")
+            }
+            result => result?,
+        }
+        let received = self.connection.received();
+        let mut console = None;
+        let mut loopback = false;
+        let mut last_error = None;
+        let attempts = self.connect_retries.max(1);
+        let start = Instant::now();
+        self.diagnostics = ConnectDiagnostics::default();
+        for i in 1..=attempts {
+            if i > 1 {
+                sleep(self.connect_retry_delay);
+            }
+            self.connection.flush()?;
+            let handshake_start = Instant::now();
+            let result = self.handshake();
+            self.diagnostics.attempts = i;
+            self.diagnostics.handshakes.push(handshake_start.elapsed());
+            self.diagnostics.elapsed = start.elapsed();
+            match result {
+                Ok(()) => {
+                    self.diagnostics.baud = self.connection.current_baud().map(|b| b.speed());
+                    log::info!("Connection Succeed");
+                    log::debug!("{:?}", self.diagnostics);
+                    return Ok(());
+                }
+                Err((e, received)) => {
+                    log::debug!("Retry {}: {}", i, e);
+                    if looks_like_console(&received) {
+                        console = Some(String::from_utf8_lossy(&received).into_owned());
+                    }
+                    // our own handshake bytes coming back
+                    loopback |= !received.is_empty() && received.iter().all(|&b| b == 0x55);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(match console {
+            Some(output) => Error::DeviceInApplication(output),
+            None if loopback => Error::HandshakeLoopback,
+            None if self.connection.received() == received => Error::NoResponse,
+            None => match last_error {
+                Some(Error::Timeout) | None => Error::ConnectionFailed(attempts),
+                Some(e) => e,
+            },
+        })
+    }
+}
+
+/// Configures a [`Flasher`] before connecting, so settings like the reset
+/// strategy and retries already apply to the connect handshake.
+pub struct FlasherBuilder {
+    initial_speed: BaudRate,
+    flash_speed: BaudRate,
+    quirks: Option<ChipQuirks>,
+    reset_strategy: Option<ResetStrategy>,
+    chunk_size: Option<usize>,
+    timeouts: TimeoutProfile,
+    connect_retries: u32,
+    connect_retry_delay: Duration,
+    reporter: Option<Box<dyn ProgressReporter>>,
+    packet_hook: Option<PacketHook>,
+    #[cfg(target_os = "linux")]
+    raw_fd: Option<std::os::unix::io::RawFd>,
+}
+
+impl Default for FlasherBuilder {
+    fn default() -> Self {
+        FlasherBuilder {
+            initial_speed: defaults::initial_baud_rate(),
+            flash_speed: defaults::baud_rate(),
+            quirks: None,
+            reset_strategy: None,
+            chunk_size: None,
+            timeouts: TimeoutProfile::default(),
+            connect_retries: defaults::connect_retries(),
+            connect_retry_delay: Duration::ZERO,
+            reporter: None,
+            packet_hook: None,
+            #[cfg(target_os = "linux")]
+            raw_fd: None,
+        }
+    }
+}
+
+impl FlasherBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Baud rate of the bootrom handshake. Defaults to
+    /// `BLFLASH_INITIAL_BAUD_RATE` or 115200.
+    pub fn initial_speed(mut self, speed: BaudRate) -> Self {
+        self.initial_speed = speed;
+        self
+    }
+
+    /// Baud rate used with the eflash_loader. Defaults to
+    /// `BLFLASH_BAUD_RATE` or 1000000.
+    pub fn flash_speed(mut self, speed: BaudRate) -> Self {
+        self.flash_speed = speed;
+        self
+    }
+
+    /// Board quirks, instead of the chip's defaults
+    pub fn quirks(mut self, quirks: ChipQuirks) -> Self {
+        self.quirks = Some(quirks);
+        self
+    }
+
+    /// Overrides the reset strategy of the quirks
+    pub fn reset_strategy(mut self, strategy: ResetStrategy) -> Self {
+        self.reset_strategy = Some(strategy);
+        self
+    }
+
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: TimeoutProfile) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    pub fn connect_retry_delay(mut self, delay: Duration) -> Self {
+        self.connect_retry_delay = delay;
+        self
+    }
+
+    pub fn progress_reporter(mut self, reporter: Box<dyn ProgressReporter>) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    /// Sees the packets of the connect handshake too
+    pub fn packet_hook(mut self, hook: PacketHook) -> Self {
+        self.packet_hook = Some(hook);
+        self
+    }
+
+    /// The tty fd of the port passed to `connect`, so baud rates outside
+    /// the standard ones can be set, see [`Connection::set_raw_fd`]
+    #[cfg(target_os = "linux")]
+    pub fn raw_fd(mut self, fd: std::os::unix::io::RawFd) -> Self {
+        self.raw_fd = Some(fd);
+        self
+    }
+
+    pub fn connect(
+        self,
+        chip: impl Chip + 'static,
+        serial: impl SerialPort + 'static,
+    ) -> Result<Flasher, Error> {
+        let initial_speed = self.initial_speed;
+        let mut flasher = self.build(chip, serial)?;
+        flasher.connection.set_baud(initial_speed)?;
+        match flasher.start_connection() {
+            Ok(()) => flasher.finish_connect()?,
+            Err(e @ (Error::NoResponse | Error::ConnectionFailed(_) | Error::LostSync)) => {
+                flasher.attach_running_loader(e)?
+            }
+            Err(e) => return Err(e),
+        }
+
+        Ok(flasher)
+    }
+
+    /// Take over an eflash_loader left running at the flash speed by an
+    /// earlier process, without resetting the device or talking to it.
+    /// Boot info is left empty.
+    pub fn attach(
+        self,
+        chip: impl Chip + 'static,
+        serial: impl SerialPort + 'static,
+    ) -> Result<Flasher, Error> {
+        let flash_speed = self.flash_speed;
+        let mut flasher = self.build(chip, serial)?;
+        flasher.connection.set_baud(flash_speed)?;
+        flasher.connection.set_timeout(flasher.timeouts.command)?;
+        flasher.loader_loaded = true;
+
+        Ok(flasher)
+    }
+
+    fn build(
+        self,
+        chip: impl Chip + 'static,
+        serial: impl SerialPort + 'static,
+    ) -> Result<Flasher, Error> {
+        let mut quirks = self.quirks.unwrap_or_else(|| chip.quirks());
+        if let Some(strategy) = self.reset_strategy {
+            quirks.reset_strategy = strategy;
+        }
+        let mut flasher = Flasher::new(chip, serial, self.initial_speed, self.flash_speed, quirks);
+        if let Some(chunk_size) = self.chunk_size {
+            flasher.set_chunk_size(chunk_size)?;
+        }
+        flasher.timeouts = self.timeouts;
+        flasher.set_connect_retries(self.connect_retries);
+        flasher.set_connect_retry_delay(self.connect_retry_delay);
+        if let Some(reporter) = self.reporter {
+            flasher.set_progress_reporter(reporter);
+        }
+        flasher.set_packet_hook(self.packet_hook);
+        #[cfg(target_os = "linux")]
+        if let Some(fd) = self.raw_fd {
+            flasher.connection.set_raw_fd(fd);
+        }
+
+        Ok(flasher)
+    }
+}
+
+/// Console output of the running application, see [`Flasher::into_monitor`].
+/// Reads block until data arrives.
+pub struct SerialMonitor(Box<dyn SerialPort>);
+
+impl SerialMonitor {
+    pub fn into_inner(self) -> Box<dyn SerialPort> {
+        self.0
+    }
+}
+
+impl Read for SerialMonitor {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// An eflash_loader session, see [`Flasher::session`]
+pub struct FlasherSession<'a> {
+    flasher: &'a mut Flasher,
+}
+
+impl Deref for FlasherSession<'_> {
+    type Target = Flasher;
+
+    fn deref(&self) -> &Flasher {
+        self.flasher
+    }
+}
+
+impl DerefMut for FlasherSession<'_> {
+    fn deref_mut(&mut self) -> &mut Flasher {
+        self.flasher
+    }
+}
+
+impl Drop for FlasherSession<'_> {
+    fn drop(&mut self) {
+        self.flasher.in_session = false;
+        if let Err(e) = self.flasher.reset() {
+            log::warn!("Failed to reset after session: {}", e);
+        }
+    }
+}
+
+/// Holds back runs of 0xFF until non-0xFF data follows them
+struct TrimWriter<W> {
+    inner: W,
+    pending: usize,
+    written: u64,
+}
+
+impl<W: Write> Write for TrimWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match buf.iter().rposition(|&b| b != 0xFF) {
+            Some(last) => {
+                let fill = vec![0xFFu8; self.pending];
+                self.inner.write_all(&fill)?;
+                self.inner.write_all(&buf[..=last])?;
+                self.written += (self.pending + last + 1) as u64;
+                self.pending = buf.len() - last - 1;
+            }
+            None => self.pending += buf.len(),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Drops writes that are entirely 0xFF, recording where the rest belong.
+/// Expects one write per dumped block.
+struct SparseWriter<W> {
+    inner: W,
+    pos: u32,
+    extents: Vec<Range<u32>>,
+}
+
+impl<W: Write> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let start = self.pos;
+        self.pos += buf.len() as u32;
+        if buf.iter().all(|&b| b == 0xFF) {
+            return Ok(buf.len());
+        }
+        self.inner.write_all(buf)?;
+        match self.extents.last_mut() {
+            Some(extent) if extent.end == start => extent.end = self.pos,
+            _ => self.extents.push(start..self.pos),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// How to initialize flash between segments
+#[derive(Debug, Clone, Copy)]
+pub struct GapFill {
+    pub byte: u8,
+    /// Leave gaps longer than this alone, e.g. to only fill padding
+    /// between closely packed images
+    pub max_gap: Option<u32>,
+}
+
+impl GapFill {
+    /// Erase sectors only, as erasing would clobber segments sharing them.
+    const SECTOR_SIZE: u32 = 4096;
+
+    /// Add fill segments for the sector aligned part of every gap.
+    fn fill<'a>(&self, mut segments: Vec<RomSegment<'a>>) -> Vec<RomSegment<'a>> {
+        segments.sort_by_key(|s| s.addr);
+        let mut fills = vec![];
+        for pair in segments.windows(2) {
+            let end = pair[0].addr + pair[0].size();
+            let start = (end + Self::SECTOR_SIZE - 1) / Self::SECTOR_SIZE * Self::SECTOR_SIZE;
+            let stop = pair[1].addr / Self::SECTOR_SIZE * Self::SECTOR_SIZE;
+            let gap = pair[1].addr.saturating_sub(end);
+            if stop <= start || self.max_gap.map_or(false, |max| gap > max) {
+                continue;
+            }
+            log::debug!("Fill gap {:x}..{:x} with {:#x}", start, stop, self.byte);
+            fills.push(RomSegment::from_vec(
+                start,
+                vec![self.byte; (stop - start) as usize],
+            ));
+        }
+        segments.extend(fills);
+        segments.sort_by_key(|s| s.addr);
+        segments
+    }
+}
+
+/// How the last bootrom handshake went, see [`Flasher::diagnostics`]
+#[derive(Debug, Clone, Default)]
+pub struct ConnectDiagnostics {
+    /// Handshakes sent, including the successful one
+    pub attempts: u32,
+    /// Duration of each handshake in order
+    pub handshakes: Vec<Duration>,
+    /// Baud rate the bootrom answered at, `None` if it never did
+    pub baud: Option<usize>,
+    /// Time from the first handshake to the last answer or timeout
+    pub elapsed: Duration,
+}
+
+/// Response timeouts per kind of operation, so a generous erase timeout
+/// doesn't also delay noticing a hung program command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutProfile {
+    /// Window for the eflash_loader to answer the handshake
+    pub handshake: Duration,
+    /// Any command without a timeout of its own
+    pub command: Duration,
+    /// Erasing a segment
+    pub erase: Duration,
+    /// Erasing the whole flash, which takes several seconds
+    pub chip_erase: Duration,
+    /// Programming one chunk
+    pub program: Duration,
+    /// Reading or hashing flash
+    pub read: Duration,
+}
+
+impl Default for TimeoutProfile {
+    fn default() -> Self {
+        let timeout = defaults::timeout();
+        TimeoutProfile {
+            handshake: Duration::from_millis(200),
+            command: timeout,
+            erase: timeout,
+            chip_erase: Duration::from_secs(60),
+            program: timeout,
+            read: timeout,
+        }
+    }
+}
+
+/// SPI flash access settings applied by the eflash_loader, see
+/// [`Flasher::set_flash_params`]. The loader derives the read command
+/// from `io_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashParams {
+    /// Pin group the flash is wired to, 0 for the internal flash
+    pub flash_pin: u8,
+    /// Flash clock divider
+    pub clock_cfg: u8,
+    /// Boot header `io_mode`: NIO, DO, QO, DIO, QIO
+    pub io_mode: u8,
+    /// Read sample delay in clock cycles
+    pub clk_delay: u8,
+}
+
+impl Default for FlashParams {
+    /// QIO at the boot header's default clock, which the GD and Winbond
+    /// parts fitted to BL602 modules support
+    fn default() -> Self {
+        FlashParams {
+            flash_pin: 0,
+            clock_cfg: 1,
+            io_mode: 4,
+            clk_delay: 1,
+        }
+    }
+}
+
+/// How [`Flasher`] checks the flash after programming
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Compare the sha256 of 64KB blocks
+    Sha256,
+    /// Read everything back and compare byte by byte, slower but pins
+    /// down the first bad byte
+    Readback,
+}
+
+/// Flash SPI read mode, valued as the boot header `io_mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    Single = 0,
+    Dual = 1,
+    Quad = 2,
+}
+
+/// What [`Flasher::plan_segments`] found would be flashed
+#[derive(Debug, Default, Clone)]
+pub struct FlashPlan {
+    pub segments: Vec<PlannedSegment>,
+}
+
+impl FlashPlan {
+    /// Bytes that would be erased and programmed
+    pub fn bytes_to_write(&self) -> u64 {
+        self.segments
+            .iter()
+            .filter(|s| s.write)
+            .map(|s| s.size as u64)
+            .sum()
     }
+}
 
-    pub fn dump_flash(&mut self, range: Range<u32>, mut writer: impl Write) -> Result<(), Error> {
-        self.load_eflash_loader()?;
+#[derive(Debug, Clone, Copy)]
+pub struct PlannedSegment {
+    pub addr: u32,
+    pub size: u32,
+    /// The flash differs, or `force` was given
+    pub write: bool,
+}
 
-        const BLOCK_SIZE: usize = 4096;
-        let mut cur = range.start;
-        let pb = get_bar(range.len() as u64);
-        while cur < range.end {
-            let data = self
-                .eflash_loader()
-                .flash_read(cur, (range.end - cur).min(BLOCK_SIZE as u32))?;
-            writer.write_all(&data)?;
-            cur += data.len() as u32;
-            pb.inc(data.len() as u64);
-        }
-        pb.finish_and_clear();
+/// Per segment results of [`Flasher::load_segments`]
+#[derive(Debug, Default, Clone)]
+pub struct FlashReport {
+    pub segments: Vec<SegmentStat>,
+    pub elapsed: Duration,
+}
 
-        Ok(())
+impl FlashReport {
+    /// Totals over all segments
+    pub fn stats(&self) -> FlashStats {
+        let mut stats = FlashStats {
+            elapsed: self.elapsed,
+            ..Default::default()
+        };
+        for segment in &self.segments {
+            stats.record(segment);
+        }
+        stats
     }
+}
 
-    pub fn load_eflash_loader(&mut self) -> Result<(), Error> {
-        let input = self.chip.get_eflash_loader().to_vec();
-        let len = input.len();
-        let mut reader = Cursor::new(input);
-        self.boot_rom().load_boot_header(&mut reader)?;
-        self.boot_rom().load_segment_header(&mut reader)?;
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentStat {
+    pub addr: u32,
+    pub size: u32,
+    /// The flash already matched the segment's sha256
+    pub skipped: bool,
+    /// Bytes programmed, counting every retry
+    pub bytes_written: u64,
+    /// Times the segment was programmed, 0 if skipped
+    pub attempts: u32,
+    pub elapsed: Duration,
+}
 
-        let start = Instant::now();
-        log::info!("Sending eflash_loader...");
-        let pb = get_bar(len as u64);
-        loop {
-            let size = self.boot_rom().load_segment_data(&mut reader)?;
-            pb.inc(size as u64);
-            if size == 0 {
-                break;
-            }
+impl SegmentStat {
+    fn skipped(segment: &RomSegment<'_>) -> Self {
+        SegmentStat {
+            addr: segment.addr,
+            size: segment.size(),
+            skipped: true,
+            bytes_written: 0,
+            attempts: 0,
+            elapsed: Duration::default(),
         }
-        pb.finish_and_clear();
-        let elapsed = start.elapsed();
-        log::info!(
-            "Finished {:?} {}/s",
-            elapsed,
-            HumanBytes((len as f64 / elapsed.as_millis() as f64 * 1000.0) as u64)
-        );
+    }
 
-        self.boot_rom().check_image()?;
-        self.boot_rom().run_image()?;
-        sleep(Duration::from_millis(500));
-        self.connection.set_baud(self.flash_speed)?;
-        self.handshake()?;
+    pub fn bytes_per_sec(&self) -> u64 {
+        (self.bytes_written as f64 / self.elapsed.as_millis().max(1) as f64 * 1000.0) as u64
+    }
+}
 
-        log::info!("Entered eflash_loader");
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlashStats {
+    pub segments: usize,
+    /// Segments skipped because the flash already matched
+    pub skipped: usize,
+    pub bytes_written: u64,
+    /// Verifications that failed and caused a segment to be written again
+    pub verify_failures: usize,
+    pub elapsed: Duration,
+}
 
-        Ok(())
+impl FlashStats {
+    fn record(&mut self, segment: &SegmentStat) {
+        self.segments += 1;
+        if segment.skipped {
+            self.skipped += 1;
+        } else {
+            self.bytes_written += segment.size as u64;
+            self.verify_failures += segment.attempts as usize - 1;
+        }
     }
+}
 
-    pub fn reset(&mut self) -> Result<(), Error> {
-        Ok(self.connection.reset()?)
-    }
+struct Guard {
+    range: Range<u32>,
+    sha256: [u8; 32],
+}
 
-    fn boot_rom(&mut self) -> BootRom {
-        BootRom(&mut self.connection)
-    }
+#[derive(Debug, Clone, Copy)]
+pub struct ChipMatch {
+    pub expected_chip: &'static str,
+    pub expected_bootrom_versions: &'static [u32],
+    pub detected_bootrom_version: u32,
+    pub matched: bool,
+}
 
-    fn eflash_loader(&mut self) -> EflashLoader {
-        EflashLoader(&mut self.connection)
-    }
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentVerify {
+    pub addr: u32,
+    pub size: u32,
+    pub matched: bool,
+    /// sha256 of the local data. Segments longer than
+    /// [`protocol::MAX_SHA256_READ_LEN`] are hashed per page and this is
+    /// the sha256 of the page digests.
+    pub expected: [u8; 32],
+    /// sha256 of the flash content, paged like `expected`
+    pub actual: [u8; 32],
+}
 
-    fn handshake(&mut self) -> Result<(), Error> {
-        self.connection
-            .with_timeout(Duration::from_millis(200), |connection| {
-                let len = connection.calc_duration_length(Duration::from_millis(5));
-                log::trace!("5ms send count {}", len);
-                let data: Vec<u8> = std::iter::repeat(0x55u8).take(len).collect();
-                let start = Instant::now();
-                connection.write_all(&data)?;
-                connection.flush()?;
-                log::trace!("handshake sent elapsed {:?}", start.elapsed());
-                sleep(Duration::from_millis(200));
-
-                for _ in 0..5 {
-                    if connection.read_response(0).is_ok() {
-                        return Ok(());
-                    }
-                }
+/// How [`Flasher::run_flashed_app`] restarted the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootMethod {
+    /// The eflash_loader accepted the reboot command
+    Command,
+    /// The command was rejected and the reset line was toggled instead
+    Reset,
+}
 
-                Err(Error::Timeout)
-            })
+#[derive(Debug, Clone, Copy)]
+pub struct SlotResult {
+    pub offset: u32,
+    /// Whether the slot was read back and matched, false when
+    /// verification is off
+    pub verified: bool,
+}
+
+/// Boot info with the known fields decoded, see
+/// [`Flasher::decoded_boot_info`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfoDecoded {
+    pub version_major: u8,
+    pub version_minor: u8,
+    /// Images must be signed to boot
+    pub secure_boot: bool,
+    /// Flash content is AES encrypted
+    pub flash_encryption: bool,
+    pub jtag_disabled: bool,
+    /// Factory chip ID, which is also the MAC address
+    pub chip_id: [u8; 6],
+}
+
+impl From<&protocol::BootInfo> for BootInfoDecoded {
+    fn from(boot_info: &protocol::BootInfo) -> Self {
+        let version = boot_info.bootrom_version.to_le_bytes();
+        let otp = &boot_info.otp_info;
+        // eFuse cfg0: sf_aes_mode [1:0], sboot_sign_mode [3:2], jtag_cfg [27:26]
+        let cfg0 = u32::from_le_bytes([otp[0], otp[1], otp[2], otp[3]]);
+        let mut chip_id = [0u8; 6];
+        chip_id.copy_from_slice(&otp[8..14]);
+        BootInfoDecoded {
+            version_major: version[0],
+            version_minor: version[1],
+            secure_boot: (cfg0 >> 2) & 0b11 != 0,
+            flash_encryption: cfg0 & 0b11 != 0,
+            jtag_disabled: (cfg0 >> 26) & 0b11 != 0,
+            chip_id,
+        }
     }
+}
 
-    fn start_connection(&mut self) -> Result<(), Error> {
-        log::info!("Start connection...");
-        self.connection.reset_to_flash()?;
-        for i in 1..=10 {
-            self.connection.flush()?;
-            if self.handshake().is_ok() {
-                log::info!("Connection Succeed");
-                return Ok(());
-            } else {
-                log::debug!("Retry {}", i);
-            }
+#[derive(Debug)]
+pub struct BootInfoLoopReport {
+    pub attempts: u32,
+    pub succeeded: u32,
+    /// Every distinct response seen, in the order first received
+    pub variants: Vec<protocol::BootInfo>,
+}
+
+impl BootInfoLoopReport {
+    pub fn success_rate(&self) -> f64 {
+        if self.attempts == 0 {
+            return 0.0;
         }
-        Err(Error::ConnectionFailed)
+        self.succeeded as f64 / self.attempts as f64
     }
 }
 
@@ -270,14 +2744,50 @@ impl<'a> BootRom<'a> {
         Ok(())
     }
 
+    /// Like `run_image` with the chip's own opcode
+    pub fn run_image_with(&mut self, opcode: u8) -> Result<(), Error> {
+        self.0.send_command(opcode, &[], 0)?;
+        Ok(())
+    }
+
+    /// Like `check_image` with the chip's own opcode
+    pub fn check_image_with(&mut self, opcode: u8) -> Result<(), Error> {
+        self.0.send_command(opcode, &[], 0)?;
+        Ok(())
+    }
+
+    /// Send the boot header after checking its length, magic and crc32.
     pub fn load_boot_header(&mut self, reader: &mut impl Read) -> Result<(), Error> {
-        let mut boot_header = vec![0u8; protocol::LOAD_BOOT_HEADER_LEN];
-        reader.read_exact(&mut boot_header)?;
+        // written by Bouffalo's tools when the crc is not checked
+        const CRC_IGNORED: u32 = 0xdeadbeef;
+
+        let mut boot_header = vec![];
+        reader
+            .take(protocol::LOAD_BOOT_HEADER_LEN as u64)
+            .read_to_end(&mut boot_header)?;
+        if boot_header.len() != protocol::LOAD_BOOT_HEADER_LEN {
+            return Err(Error::InvalidBootHeader("image too short"));
+        }
+        let header = match ImageHeader::parse(&boot_header) {
+            Ok(header) => header,
+            Err(Error::InvalidImageHeader(_)) => return Err(Error::InvalidBootHeader("bad magic")),
+            Err(e) => return Err(e),
+        };
+        if !header.crc_valid && header.crc32 != CRC_IGNORED {
+            return Err(Error::InvalidBootHeader("crc32 mismatch"));
+        }
         self.0.command(protocol::LoadBootHeader { boot_header })?;
         Ok(())
     }
 
-    pub fn load_segment_header(&mut self, reader: &mut impl Read) -> Result<(), Error> {
+    /// Send the segment header. A different echo from the device fails
+    /// with `Error::SegmentHeaderMismatch` if `strict`, and is only logged
+    /// otherwise.
+    pub fn load_segment_header(
+        &mut self,
+        reader: &mut impl Read,
+        strict: bool,
+    ) -> Result<(), Error> {
         let mut segment_header = vec![0u8; protocol::LOAD_SEGMENT_HEADER_LEN];
         reader.read_exact(&mut segment_header)?;
 
@@ -290,67 +2800,323 @@ impl<'a> BootRom<'a> {
                 "Segment header not match req:{:x?} != resp:{:x?}",
                 segment_header,
                 resp.data
-            )
+            );
+            if strict {
+                return Err(Error::SegmentHeaderMismatch {
+                    ranges: mismatched_ranges(&segment_header, &resp.data),
+                    sent: segment_header,
+                    received: resp.data,
+                });
+            }
         }
 
         Ok(())
     }
 
-    pub fn load_segment_data(&mut self, reader: &mut impl Read) -> Result<u32, Error> {
-        let mut segment_data = vec![0u8; 4000];
+    pub fn load_segment_data(
+        &mut self,
+        reader: &mut impl Read,
+        chunk_size: usize,
+    ) -> Result<u32, Error> {
+        let mut segment_data = vec![0u8; chunk_size];
         let size = reader.read(&mut segment_data)?;
         if size == 0 {
             return Ok(0);
         }
         segment_data.truncate(size);
 
+        self.0.throttle(size);
         self.0.command(protocol::LoadSegmentData { segment_data })?;
 
         Ok(size as u32)
     }
 
+    /// Send all of `reader` keeping up to `window` packets in flight, so the
+    /// next packet is on the wire while the previous one is acked. Acks are
+    /// read in order and the first failing one is returned. `on_ack` is
+    /// called with the size of each acked packet.
+    pub fn load_segment_data_pipelined(
+        &mut self,
+        reader: &mut impl Read,
+        chunk_size: usize,
+        window: usize,
+        mut on_ack: impl FnMut(u32) -> Result<(), Error>,
+    ) -> Result<u32, Error> {
+        let mut in_flight = VecDeque::new();
+        let mut sent = 0;
+        let mut acked = 0;
+        let mut done = false;
+        loop {
+            while !done && in_flight.len() < window.max(1) {
+                let mut segment_data = vec![0u8; chunk_size];
+                let size = reader.read(&mut segment_data)?;
+                if size == 0 {
+                    done = true;
+                    break;
+                }
+                segment_data.truncate(size);
+                self.0.throttle(size);
+                self.0
+                    .write_command(protocol::LoadSegmentData { segment_data })?;
+                in_flight.push_back(size as u32);
+                sent += size as u32;
+            }
+            let size = match in_flight.pop_front() {
+                Some(size) => size,
+                None => return Ok(acked),
+            };
+            if let Err(e) = self.0.read_command_response::<protocol::LoadSegmentData>() {
+                log::warn!(
+                    "Segment data at {:x} failed with {} of {} bytes unacked",
+                    acked,
+                    sent - acked,
+                    sent
+                );
+                return Err(e);
+            }
+            acked += size;
+            on_ack(size)?;
+        }
+    }
+
     pub fn get_boot_info(&mut self) -> Result<protocol::BootInfo, Error> {
         self.0.command(protocol::BootInfoReq {})
     }
 }
 
-pub struct EflashLoader<'a>(&'a mut Connection);
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProgramChunk {
+    /// Bytes taken from the reader, 0 at the end of input
+    pub size: u32,
+    /// Fewer than `chunk_size` bytes were available
+    pub partial: bool,
+    /// 0xFF bytes appended for alignment
+    pub padding: u32,
+}
+
+pub struct EflashLoader<'a>(&'a mut Connection, TimeoutProfile);
 
 impl<'a> EflashLoader<'a> {
+    /// The only flash checksum the eflash_loader computes. It has no CRC
+    /// command, and a CRC over data read back with `flash_read` would be
+    /// bound by the serial link, far slower than hashing on the device.
+    ///
+    /// The range is limited to [`protocol::MAX_SHA256_READ_LEN`].
     pub fn sha256_read(&mut self, addr: u32, len: u32) -> Result<[u8; 32], Error> {
-        Ok(self.0.command(protocol::Sha256Read { addr, len })?.digest)
+        if len > protocol::MAX_SHA256_READ_LEN || addr.checked_add(len).is_none() {
+            return Err(Error::InvalidFlashRange { addr, len });
+        }
+        self.0.with_timeout(self.1.read, |connection| {
+            Ok(connection
+                .command(protocol::Sha256Read { addr, len })?
+                .digest)
+        })
     }
 
+    /// Digest of a range of any length, hashed on the device in
+    /// [`protocol::MAX_SHA256_READ_LEN`] pages. Matches a local
+    /// `PagedHasher` over the same bytes.
+    pub fn sha256_read_paged(&mut self, addr: u32, len: u32) -> Result<[u8; 32], Error> {
+        let end = addr
+            .checked_add(len)
+            .ok_or(Error::InvalidFlashRange { addr, len })?;
+        let pages = (addr..end)
+            .step_by(protocol::MAX_SHA256_READ_LEN as usize)
+            .map(|start| self.sha256_read(start, protocol::MAX_SHA256_READ_LEN.min(end - start)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(combine_pages(&pages))
+    }
+
+    /// Read `size` bytes, requesting them again when the response's length
+    /// prefix comes back short.
     pub fn flash_read(&mut self, addr: u32, size: u32) -> Result<Vec<u8>, Error> {
-        Ok(self.0.command(protocol::FlashRead { addr, size })?.data)
+        const RETRIES: u32 = 3;
+
+        let mut retry = 0;
+        loop {
+            let resp = self.0.with_timeout(self.1.read, |connection| {
+                connection.command(protocol::FlashRead { addr, size })
+            })?;
+            match resp.validate(size) {
+                Ok(()) => return Ok(resp.data),
+                Err(e) if retry == RETRIES => return Err(e),
+                Err(_) => {}
+            }
+            retry += 1;
+            log::warn!(
+                "Short read at {:x}: {}/{} bytes, retry {}/{}",
+                addr,
+                resp.data.len(),
+                size,
+                retry,
+                RETRIES
+            );
+        }
     }
 
-    pub fn flash_program(&mut self, addr: u32, reader: &mut impl Read) -> Result<u32, Error> {
-        let mut data = vec![0u8; 4000];
+    /// Program up to `chunk_size` bytes from `reader`. A partial chunk is
+    /// padded with 0xFF to a multiple of `pad_to` if given, which leaves
+    /// the erased flash beyond the data unchanged.
+    pub fn flash_program(
+        &mut self,
+        addr: u32,
+        reader: &mut impl Read,
+        chunk_size: usize,
+        pad_to: Option<u32>,
+    ) -> Result<ProgramChunk, Error> {
+        let mut data = vec![0u8; chunk_size];
         let size = reader.read(&mut data)?;
         if size == 0 {
-            return Ok(0);
+            return Ok(ProgramChunk::default());
         }
         data.truncate(size);
 
-        self.0.command(protocol::FlashProgram { addr, data })?;
+        let partial = size < chunk_size;
+        if let (true, Some(align)) = (partial, pad_to) {
+            let align = align.max(1) as usize;
+            data.resize((size + align - 1) / align * align, 0xFF);
+        }
+        let padding = (data.len() - size) as u32;
 
-        Ok(size as u32)
+        self.0.throttle(data.len());
+        self.0.with_timeout(self.1.program, |connection| {
+            connection.command(protocol::FlashProgram { addr, data })
+        })?;
+
+        Ok(ProgramChunk {
+            size: size as u32,
+            partial,
+            padding,
+        })
+    }
+
+    pub fn get_boot_info(&mut self) -> Result<protocol::BootInfo, Error> {
+        self.0.command(protocol::BootInfoReq {})
+    }
+
+    pub fn flash_read_jedec_id(&mut self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.command(protocol::FlashReadJedecId {})?.data)
+    }
+
+    /// `addr` is an offset into the eFuse region, the loader adds the base.
+    pub fn efuse_read(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, Error> {
+        Ok(self.0.command(protocol::EfuseRead { addr, len })?.data)
+    }
+
+    /// The MAC is stored as two little-endian words, so the bytes read
+    /// back are in reverse of the printed order.
+    pub fn read_mac(&mut self) -> Result<[u8; 6], Error> {
+        let data = self.efuse_read(protocol::EFUSE_MAC_OFFSET, 8)?;
+        if data.len() < 6 {
+            return Err(Error::RespError);
+        }
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&data[..6]);
+        mac.reverse();
+        Ok(mac)
+    }
+
+    pub fn flash_chip_erase(&mut self) -> Result<(), Error> {
+        log::info!("Erase whole flash...");
+        self.0.with_timeout(self.1.chip_erase, |connection| {
+            connection.command(protocol::FlashChipErase {})?;
+            Ok(())
+        })
+    }
+
+    pub fn reboot(&mut self) -> Result<(), Error> {
+        self.0.command(protocol::Reset {})?;
+        Ok(())
+    }
+
+    pub fn mem_read(&mut self, addr: u32) -> Result<u32, Error> {
+        let data = self.0.command(protocol::MemRead { addr, len: 4 })?.data;
+        if data.len() != 4 {
+            return Err(Error::RespError);
+        }
+        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    pub fn mem_write(&mut self, addr: u32, value: u32) -> Result<(), Error> {
+        self.0.command(protocol::MemWrite { addr, value })?;
+        Ok(())
+    }
+
+    pub fn flash_set_io_mode(&mut self, io_mode: u8) -> Result<(), Error> {
+        self.flash_set_para(FlashParams {
+            io_mode,
+            clk_delay: 0,
+            ..Default::default()
+        })
+    }
+
+    pub fn flash_set_para(&mut self, params: FlashParams) -> Result<(), Error> {
+        self.0.command(protocol::FlashSetPara {
+            flash_pin: params.flash_pin,
+            clock_cfg: params.clock_cfg,
+            io_mode: params.io_mode,
+            clk_delay: params.clk_delay,
+        })?;
+        Ok(())
+    }
+
+    /// Tell the loader the last chunk was sent, for loaders that buffer
+    /// writes. A loader rejecting it has nothing left to commit.
+    pub fn flash_program_end(&mut self) -> Result<(), Error> {
+        match self.0.command(protocol::FlashProgramEnd {}) {
+            Err(e @ Error::CommandFailed { .. }) => {
+                log::debug!("Flash program end not supported: {}", e);
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }
     }
 
     pub fn flash_erase(&mut self, start: u32, end: u32) -> Result<(), Error> {
-        self.0.command(protocol::FlashErase { start, end })?;
+        self.0.with_timeout(self.1.erase, |connection| {
+            connection.command(protocol::FlashErase { start, end })
+        })?;
+
+        Ok(())
+    }
+
+    /// SR1 and SR2 read one byte at a time, SR1 in the low byte
+    pub fn read_flash_status(&mut self) -> Result<u16, Error> {
+        let mut status = 0;
+        for (i, &cmd) in [SPI_READ_STATUS1, SPI_READ_STATUS2].iter().enumerate() {
+            let resp = self
+                .0
+                .command(protocol::FlashReadStatusReg { cmd, len: 1 })?;
+            let byte = *resp.data.first().ok_or(Error::ShortRead {
+                requested: 1,
+                got: 0,
+            })?;
+            status |= (byte as u16) << (8 * i);
+        }
+        Ok(status)
+    }
 
+    /// SR1 and SR2 in one two byte write, SR1 from the low byte
+    pub fn write_flash_status(&mut self, value: u16) -> Result<(), Error> {
+        self.0.command(protocol::FlashWriteStatusReg {
+            cmd: SPI_WRITE_STATUS,
+            len: 2,
+            value: value as u32,
+        })?;
         Ok(())
     }
 }
 
-mod protocol {
+pub mod protocol {
     use crate::connection::{Command, Response};
+    use crate::Error;
     use deku::prelude::*;
 
     pub const LOAD_BOOT_HEADER_LEN: usize = 176;
     pub const LOAD_SEGMENT_HEADER_LEN: usize = 16;
+    /// Largest data chunk whose command still fits the 16-bit length field
+    /// along with its 4 byte address
+    pub const MAX_CHUNK_SIZE: usize = u16::MAX as usize - 4;
 
     #[derive(Debug, DekuWrite, Default)]
     pub struct CheckImage {}
@@ -362,7 +3128,7 @@ mod protocol {
 
     #[derive(Debug, DekuWrite, Default)]
     pub struct BootInfoReq {}
-    #[derive(Debug, DekuRead, Default)]
+    #[derive(Debug, DekuRead, Default, Clone, PartialEq)]
     pub struct BootInfo {
         pub len: u16,
         pub bootrom_version: u32,
@@ -403,6 +3169,10 @@ mod protocol {
     }
     impl_command!(0x30, FlashErase);
 
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashChipErase {}
+    impl_command!(0x3c, FlashChipErase);
+
     #[derive(Debug, DekuWrite, Default)]
     pub struct FlashProgram {
         pub addr: u32,
@@ -410,6 +3180,12 @@ mod protocol {
     }
     impl_command!(0x31, FlashProgram);
 
+    /// Waits for the flash to finish programming, `flash_write_check` in
+    /// Bouffalo's tools
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashProgramEnd {}
+    impl_command!(0x3a, FlashProgramEnd);
+
     #[derive(Debug, DekuWrite, Default)]
     pub struct FlashRead {
         pub addr: u32,
@@ -423,6 +3199,104 @@ mod protocol {
     }
     impl_command!(0x32, FlashRead, FlashReadResp);
 
+    impl FlashReadResp {
+        /// Check that the length prefix matches both the data received
+        /// and the `requested` size
+        pub fn validate(&self, requested: u32) -> Result<(), Error> {
+            if self.data.len() != self.len as usize || self.len as u32 != requested {
+                return Err(Error::ShortRead {
+                    requested,
+                    got: self.data.len() as u32,
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashReadJedecId {}
+    #[derive(Debug, DekuRead)]
+    pub struct FlashReadJedecIdResp {
+        pub len: u16,
+        #[deku(count = "len")]
+        pub data: Vec<u8>,
+    }
+    impl_command!(0x36, FlashReadJedecId, FlashReadJedecIdResp);
+
+    /// Read `len` bytes of the status register selected by the SPI read
+    /// command `cmd`
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashReadStatusReg {
+        pub cmd: u32,
+        pub len: u32,
+    }
+    #[derive(Debug, DekuRead)]
+    pub struct FlashReadStatusRegResp {
+        pub len: u16,
+        #[deku(count = "len")]
+        pub data: Vec<u8>,
+    }
+    impl_command!(0x37, FlashReadStatusReg, FlashReadStatusRegResp);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashWriteStatusReg {
+        pub cmd: u32,
+        pub len: u32,
+        pub value: u32,
+    }
+    impl_command!(0x38, FlashWriteStatusReg);
+
+    /// Where the eFuse region is memory mapped, e.g. for `read_reg`, and
+    /// its size in bytes
+    pub const EFUSE_BASE: u32 = 0x4000_7000;
+    pub const EFUSE_SIZE: u32 = 128;
+    /// Offset of the factory MAC address in the eFuse region
+    pub const EFUSE_MAC_OFFSET: u32 = 0x14;
+
+    /// Opcode 0x41 in the loader's table, 0x42 is efuse_read_mac
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct EfuseRead {
+        /// Offset into the eFuse region, not the `EFUSE_BASE` address
+        pub addr: u32,
+        pub len: u32,
+    }
+    #[derive(Debug, DekuRead)]
+    pub struct EfuseReadResp {
+        pub len: u16,
+        #[deku(count = "len")]
+        pub data: Vec<u8>,
+    }
+    impl_command!(0x41, EfuseRead, EfuseReadResp);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct Reset {}
+    impl_command!(0x21, Reset);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct MemWrite {
+        pub addr: u32,
+        pub value: u32,
+    }
+    impl_command!(0x50, MemWrite);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct MemRead {
+        pub addr: u32,
+        pub len: u32,
+    }
+    #[derive(Debug, DekuRead)]
+    pub struct MemReadResp {
+        pub len: u16,
+        #[deku(count = "len")]
+        pub data: Vec<u8>,
+    }
+    impl_command!(0x51, MemRead, MemReadResp);
+
+    /// Largest range hashed by one `Sha256Read`. Longer ranges are hashed
+    /// in pages of this size, see `EflashLoader::sha256_read_paged`, which
+    /// also keeps each request well within the read timeout.
+    pub const MAX_SHA256_READ_LEN: u32 = 0x10_0000;
+
     #[derive(Debug, DekuWrite, Default)]
     pub struct Sha256Read {
         pub addr: u32,
@@ -435,4 +3309,291 @@ mod protocol {
         pub digest: [u8; 32],
     }
     impl_command!(0x3d, Sha256Read, Sha256ReadResp);
+
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashSetPara {
+        pub flash_pin: u8,
+        pub clock_cfg: u8,
+        pub io_mode: u8,
+        pub clk_delay: u8,
+    }
+    impl_command!(0x3b, FlashSetPara);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mismatched_ranges_reports_every_run() {
+        let a = [0u8; 16];
+        let mut b = [0u8; 16];
+        b[1] = 1;
+        b[2] = 1;
+        b[7] = 1;
+        b[10] = 1;
+        b[11] = 1;
+        b[15] = 1;
+        assert_eq!(mismatched_ranges(&a, &b), vec![1..3, 7..8, 10..12, 15..16]);
+        assert!(mismatched_ranges(&a, &a).is_empty());
+    }
+
+    #[test]
+    fn mismatched_ranges_includes_length_difference() {
+        assert_eq!(mismatched_ranges(&[1, 2, 3, 4], &[1, 2]), vec![2..4]);
+        assert_eq!(mismatched_ranges(&[1, 9], &[1, 2, 3]), vec![1..3]);
+    }
+
+    #[test]
+    fn verify_continues_past_mismatches() {
+        let segments = (0..5u32)
+            .map(|i| RomSegment::from_vec(i * 0x1000, vec![i as u8; 0x1000]))
+            .collect::<Vec<_>>();
+        let hashes = local_hashes(&segments);
+        let mut flash = segments
+            .iter()
+            .flat_map(|s| s.data.iter().copied())
+            .collect::<Vec<u8>>();
+        // corrupt the first, middle and last segment
+        flash[0x10] ^= 0xff;
+        flash[0x2fff] ^= 0xff;
+        flash[0x4800] ^= 0xff;
+
+        let mut reads = vec![];
+        let results = verify_with(&segments, &hashes, |addr, len| {
+            reads.push(addr);
+            let range = addr as usize..(addr + len) as usize;
+            Ok(Sha256::digest(&flash[range]).into())
+        })
+        .unwrap();
+
+        assert_eq!(reads, vec![0, 0x1000, 0x2000, 0x3000, 0x4000]);
+        let mismatched = results
+            .iter()
+            .filter(|r| !r.matched)
+            .map(|r| r.addr)
+            .collect::<Vec<_>>();
+        assert_eq!(mismatched, vec![0, 0x2000, 0x4000]);
+        for result in &results {
+            assert_eq!(result.size, 0x1000);
+            assert_eq!(result.expected, hashes[&result.addr]);
+            assert_eq!(result.matched, result.expected == result.actual);
+        }
+    }
+
+    #[test]
+    fn paged_hash_matches_sha256_within_a_page() {
+        let data = vec![0xa5u8; 0x1234];
+        let expected: [u8; 32] = Sha256::digest(&data).into();
+        assert_eq!(PagedHasher::digest(&data), expected);
+        let empty: [u8; 32] = Sha256::digest(&[]).into();
+        assert_eq!(PagedHasher::digest(&[]), empty);
+    }
+
+    #[test]
+    fn paged_hash_combines_page_digests() {
+        let page = protocol::MAX_SHA256_READ_LEN as usize;
+        let data = (0..page + 10).map(|i| i as u8).collect::<Vec<u8>>();
+        let first: [u8; 32] = Sha256::digest(&data[..page]).into();
+        let rest: [u8; 32] = Sha256::digest(&data[page..]).into();
+        let expected: [u8; 32] = Sha256::digest(&[first, rest].concat()).into();
+        assert_eq!(PagedHasher::digest(&data), expected);
+
+        // an exact page is a single page
+        assert_eq!(PagedHasher::digest(&data[..page]), first);
+
+        // the split of the updates doesn't matter
+        let mut hasher = PagedHasher::default();
+        for chunk in data.chunks(4093) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finalize(), expected);
+    }
+
+    #[test]
+    fn verify_stops_on_read_error() {
+        let segments = vec![
+            RomSegment::from_vec(0, vec![0; 16]),
+            RomSegment::from_vec(0x1000, vec![0; 16]),
+        ];
+        let hashes = local_hashes(&segments);
+        let result = verify_with(&segments, &hashes, |_, _| Err(Error::Timeout));
+        assert!(matches!(result, Err(Error::Timeout)));
+    }
+
+    /// A flasher that hasn't talked to a device, over a local TCP socket
+    fn idle_flasher() -> Flasher {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let serial = crate::TcpSerial::connect(listener.local_addr().unwrap()).unwrap();
+        Flasher::new(
+            crate::chip::bl602::Bl602,
+            serial,
+            BaudRate::Baud115200,
+            BaudRate::Baud115200,
+            ChipQuirks::default(),
+        )
+    }
+
+    #[test]
+    fn chip_erase_allowed_unless_protection_enabled() {
+        let mut flasher = idle_flasher();
+        assert!(flasher.check_protected(0..u32::MAX).is_ok());
+        assert!(flasher.check_protected(0..0x1000).is_ok());
+
+        flasher.protect_chip_ranges();
+        assert!(matches!(
+            flasher.check_protected(0..u32::MAX),
+            Err(Error::ProtectedRegion { addr: 0 })
+        ));
+        assert!(flasher.check_protected(0x2000..0x3000).is_ok());
+
+        flasher.set_allow_protected(true);
+        assert!(flasher.check_protected(0..u32::MAX).is_ok());
+    }
+
+    #[test]
+    fn trim_writer_drops_trailing_ff() {
+        let mut writer = TrimWriter {
+            inner: vec![],
+            pending: 0,
+            written: 0,
+        };
+        writer.write_all(&[1, 2, 0xFF, 0xFF]).unwrap();
+        writer.write_all(&[0xFF]).unwrap();
+        writer.write_all(&[3, 0xFF]).unwrap();
+        writer.write_all(&[0xFF; 8]).unwrap();
+        assert_eq!(writer.inner, vec![1, 2, 0xFF, 0xFF, 0xFF, 3]);
+        assert_eq!(writer.written, 6);
+        assert_eq!(writer.pending, 9);
+    }
+
+    #[test]
+    fn gap_fill_covers_whole_sectors_between_segments() {
+        let fill = GapFill {
+            byte: 0x00,
+            max_gap: None,
+        };
+        let segments = fill.fill(vec![
+            RomSegment::from_vec(0x3000, vec![1; 0x10]),
+            RomSegment::from_vec(0x0, vec![1; 0x100]),
+            // shares the first sector, leaves no whole sector to fill
+            RomSegment::from_vec(0x800, vec![1; 0x100]),
+        ]);
+        let layout = segments
+            .iter()
+            .map(|s| (s.addr, s.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            layout,
+            vec![
+                (0x0, 0x100),
+                (0x800, 0x100),
+                (0x1000, 0x2000),
+                (0x3000, 0x10)
+            ]
+        );
+        assert!(segments[2].data.iter().all(|&b| b == 0x00));
+    }
+
+    #[test]
+    fn gap_fill_skips_gaps_over_max() {
+        let fill = GapFill {
+            byte: 0xFF,
+            max_gap: Some(0x1000),
+        };
+        let segments = fill.fill(vec![
+            RomSegment::from_vec(0x0, vec![1; 0x1000]),
+            RomSegment::from_vec(0x2000, vec![1; 0x1000]),
+            RomSegment::from_vec(0x10000, vec![1; 0x1000]),
+        ]);
+        let layout = segments
+            .iter()
+            .map(|s| (s.addr, s.size()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            layout,
+            vec![
+                (0x0, 0x1000),
+                (0x1000, 0x1000),
+                (0x2000, 0x1000),
+                (0x10000, 0x1000)
+            ]
+        );
+    }
+
+    #[test]
+    fn command_encode_frames_payload() {
+        use crate::connection::Command;
+
+        let packet = protocol::FlashRead {
+            addr: 0x2000,
+            size: 0x100,
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(
+            packet,
+            vec![0x32, 0x00, 0x08, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00]
+        );
+
+        let packet = protocol::FlashProgramEnd {}.encode().unwrap();
+        assert_eq!(packet, vec![0x3a, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn flash_size_from_capacity_byte() {
+        assert_eq!(flash_size(0xc84015).unwrap(), 2 * 1024 * 1024);
+        assert_eq!(flash_size(0xef4016).unwrap(), 4 * 1024 * 1024);
+        assert_eq!(flash_size(0xef4018).unwrap(), 16 * 1024 * 1024);
+        assert!(matches!(
+            flash_size(0xef4012),
+            Err(Error::UnknownFlashSize(0x12))
+        ));
+        assert!(matches!(
+            flash_size(0xef4019),
+            Err(Error::UnknownFlashSize(0x19))
+        ));
+    }
+
+    #[test]
+    fn sparse_writer_records_extents() {
+        let mut writer = SparseWriter {
+            inner: vec![],
+            pos: 0,
+            extents: vec![],
+        };
+        for block in &[[1u8; 4], [0xFF; 4], [2; 4], [3; 4], [0xFF; 4]] {
+            writer.write_all(block).unwrap();
+        }
+        assert_eq!(writer.inner, vec![1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]);
+        assert_eq!(writer.extents, vec![0..4, 8..16]);
+        assert_eq!(writer.pos, 20);
+    }
+
+    #[test]
+    fn flash_read_resp_validate() {
+        let resp = protocol::FlashReadResp {
+            len: 4,
+            data: vec![0; 4],
+        };
+        assert!(resp.validate(4).is_ok());
+        assert!(matches!(
+            resp.validate(8),
+            Err(Error::ShortRead {
+                requested: 8,
+                got: 4
+            })
+        ));
+        let truncated = protocol::FlashReadResp {
+            len: 4,
+            data: vec![0; 3],
+        };
+        assert!(matches!(
+            truncated.validate(4),
+            Err(Error::ShortRead {
+                requested: 4,
+                got: 3
+            })
+        ));
+    }
 }
@@ -1,5 +1,5 @@
-use crate::Error;
-use byteorder::{NativeEndian, ReadBytesExt};
+use crate::{chip::FlashCapabilities, flasher::protocol::LOAD_SEGMENT_HEADER_LEN, Error};
+use byteorder::{LittleEndian, NativeEndian, ReadBytesExt, WriteBytesExt};
 use deku::prelude::*;
 use serde::Deserialize;
 use sha2::{Digest, Sha256};
@@ -11,7 +11,7 @@ pub struct BootHeaderCfgFile {
     pub boot_header_cfg: BootHeaderCfg,
 }
 
-#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+#[derive(Debug, Deserialize, DekuRead, DekuWrite, Default, Clone)]
 pub struct FlashCfg {
     flashcfg_magic_code: u32,
     // 12
@@ -118,7 +118,7 @@ pub struct FlashCfg {
     flashcfg_crc32: u32,
 }
 
-#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+#[derive(Debug, Deserialize, DekuRead, DekuWrite, Default, Clone)]
 pub struct ClkCfg {
     // 100
     clkcfg_magic_code: u32,
@@ -139,7 +139,7 @@ pub struct ClkCfg {
 
 // NOTE: the order is reversed here
 // see: https://github.com/sharksforarms/deku/issues/134
-#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+#[derive(Debug, Deserialize, DekuRead, DekuWrite, Default, Clone)]
 pub struct BootCfg {
     // 116
     #[deku(bits = 2)]
@@ -191,7 +191,7 @@ pub struct BootCfg {
     _unused3: [u8; 8],
 }
 
-#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+#[derive(Debug, Deserialize, DekuRead, DekuWrite, Default, Clone)]
 pub struct BootHeaderCfg {
     magic_code: u32,
     revision: u32,
@@ -210,6 +210,56 @@ pub struct BootHeaderCfg {
     crc32: u32,
 }
 
+/// Summary of a boot header read back from flash
+#[derive(Debug, Clone)]
+pub struct ImageHeader {
+    pub magic_code: u32,
+    pub revision: u32,
+    pub entry: u32,
+    pub img_start: u32,
+    /// Image length, or segment count for images loaded to RAM
+    pub img_len: u32,
+    pub sha256: [u8; 32],
+    pub crc32: u32,
+    pub crc_valid: bool,
+}
+
+impl ImageHeader {
+    pub const MAGIC_CODE: u32 = 0x504e4642;
+
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let (_, cfg) = BootHeaderCfg::from_bytes((data, 0))?;
+        if cfg.magic_code != Self::MAGIC_CODE {
+            return Err(Error::InvalidImageHeader(cfg.magic_code));
+        }
+        let boot_cfg = &cfg.boot_cfg;
+        let mut sha256 = [0u8; 32];
+        let mut writer = Cursor::new(&mut sha256[..]);
+        for word in &[
+            boot_cfg.hash_0,
+            boot_cfg.hash_1,
+            boot_cfg.hash_2,
+            boot_cfg.hash_3,
+            boot_cfg.hash_4,
+            boot_cfg.hash_5,
+            boot_cfg.hash_6,
+            boot_cfg.hash_7,
+        ] {
+            writer.write_u32::<NativeEndian>(*word)?;
+        }
+        Ok(ImageHeader {
+            magic_code: cfg.magic_code,
+            revision: cfg.revision,
+            entry: boot_cfg.bootentry,
+            img_start: boot_cfg.img_start,
+            img_len: boot_cfg.img_len,
+            sha256,
+            crc32: cfg.crc32,
+            crc_valid: cfg.checksum() == cfg.crc32,
+        })
+    }
+}
+
 impl FlashCfg {
     fn checksum(&self) -> u32 {
         let data = self.to_bytes().unwrap();
@@ -241,6 +291,16 @@ impl BootHeaderCfg {
         self.boot_cfg.hash_7 = reader.read_u32::<NativeEndian>()?;
         Ok(())
     }
+    /// Check the requested flash mode against what the chip supports
+    pub fn validate(&self, caps: &FlashCapabilities) -> Result<(), Error> {
+        if !caps.io_modes.contains(&self.flash_cfg.io_mode) {
+            return Err(Error::UnsupportedFlashMode(self.flash_cfg.io_mode));
+        }
+        if !caps.flash_clk_types.contains(&self.clk_cfg.flash_clk_type) {
+            return Err(Error::UnsupportedFlashClock(self.clk_cfg.flash_clk_type));
+        }
+        Ok(())
+    }
     pub fn make_image(&mut self, offset: usize, mut image: Vec<u8>) -> Result<Vec<u8>, Error> {
         let binlen = ((image.len() + 15) / 16) * 16;
         image.resize(binlen, 0xFF);
@@ -258,4 +318,87 @@ impl BootHeaderCfg {
 
         Ok(header)
     }
+    /// Build an image the bootrom loads to RAM at `entry` and runs, laid
+    /// out like the eflash_loader: boot header, one segment header, data.
+    pub fn make_ram_image(&mut self, entry: u32, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut data = data.to_vec();
+        data.resize((data.len() + 15) / 16 * 16, 0xFF);
+
+        let mut segment = Vec::with_capacity(LOAD_SEGMENT_HEADER_LEN + data.len());
+        segment.write_u32::<LittleEndian>(entry)?;
+        segment.write_u32::<LittleEndian>(data.len() as u32)?;
+        segment.write_u32::<LittleEndian>(0)?;
+        let crc32 = crc::crc32::checksum_ieee(&segment);
+        segment.write_u32::<LittleEndian>(crc32)?;
+        segment.extend_from_slice(&data);
+
+        let hash = Sha256::digest(&segment);
+        self.update_sha256(&hash[..])?;
+        self.boot_cfg.no_segment = 0;
+        // segment count for images loaded to RAM
+        self.boot_cfg.img_len = 1;
+        self.boot_cfg.bootentry = entry;
+        self.boot_cfg.img_start = entry;
+        self.flash_cfg.update()?;
+        self.clk_cfg.update()?;
+        self.update()?;
+
+        let mut image = self.to_bytes()?;
+        image.append(&mut segment);
+
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flasher::protocol::LOAD_BOOT_HEADER_LEN as HEADER_LEN;
+    use crate::image::build_image;
+    use byteorder::ByteOrder;
+
+    #[test]
+    fn ram_image_layout() {
+        let entry = 0x2201_0000;
+        let data = [0x5a; 20];
+        let image = build_image(entry, &data).unwrap();
+        assert_eq!(image.len(), HEADER_LEN + LOAD_SEGMENT_HEADER_LEN + 32);
+
+        let segment = &image[HEADER_LEN..];
+        assert_eq!(LittleEndian::read_u32(&segment[0..4]), entry);
+        assert_eq!(LittleEndian::read_u32(&segment[4..8]), 32);
+        assert_eq!(LittleEndian::read_u32(&segment[8..12]), 0);
+        assert_eq!(
+            LittleEndian::read_u32(&segment[12..16]),
+            crc::crc32::checksum_ieee(&segment[0..12])
+        );
+        assert_eq!(&segment[16..36], &data[..]);
+        assert!(segment[36..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn parse_ram_image_header() {
+        let entry = 0x2201_0000;
+        let image = build_image(entry, &[1, 2, 3]).unwrap();
+        let header = ImageHeader::parse(&image).unwrap();
+        assert_eq!(header.magic_code, ImageHeader::MAGIC_CODE);
+        assert_eq!(header.entry, entry);
+        assert_eq!(header.img_start, entry);
+        assert_eq!(header.img_len, 1);
+        assert!(header.crc_valid);
+        assert_eq!(header.sha256[..], Sha256::digest(&image[HEADER_LEN..])[..]);
+    }
+
+    #[test]
+    fn parse_rejects_bad_magic_and_crc() {
+        let mut image = build_image(0x2201_0000, &[1, 2, 3]).unwrap();
+        image[HEADER_LEN - 1] ^= 0xff;
+        assert!(!ImageHeader::parse(&image).unwrap().crc_valid);
+
+        image[0] ^= 0xff;
+        assert!(matches!(
+            ImageHeader::parse(&image),
+            Err(Error::InvalidImageHeader(magic)) if magic == ImageHeader::MAGIC_CODE ^ 0xff
+        ));
+    }
 }
@@ -1,5 +1,16 @@
 mod bootheader;
 mod partition;
 
-pub use bootheader::{BootHeaderCfg, BootHeaderCfgFile};
+pub use bootheader::{BootHeaderCfg, BootHeaderCfgFile, ImageHeader};
 pub use partition::PartitionCfg;
+
+use crate::{chip::bl602, Error};
+
+/// Build a BL602 image that is loaded to RAM at `entry` and run from
+/// there, like the eflash_loader, using the default boot header config.
+pub fn build_image(entry: u32, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let BootHeaderCfgFile {
+        mut boot_header_cfg,
+    } = toml::from_slice(bl602::DEFAULT_BOOTHEADER_CFG)?;
+    boot_header_cfg.make_ram_image(entry, data)
+}
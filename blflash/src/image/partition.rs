@@ -1,8 +1,10 @@
-use deku::prelude::*;
+use crate::{elf::RomSegment, Error};
 use bitvec::prelude::*;
+use deku::prelude::*;
 use serde::Deserialize;
 use std::io::Write;
 use std::iter;
+use std::{fs, io::ErrorKind, ops::Range, path::Path};
 
 #[derive(Debug, Deserialize, DekuWrite, Default)]
 #[deku(magic = b"\x42\x46\x50\x54\x00\x00")]
@@ -46,6 +48,44 @@ pub struct Entry {
 }
 
 impl PartitionCfg {
+    /// Segments for every entry with a `<name>.bin` file in `dir`, written
+    /// to the entry's first address. Entries must not overlap and each
+    /// file must fit its entry.
+    pub fn load_segments(&self, dir: &Path) -> Result<Vec<RomSegment<'static>>, Error> {
+        for (i, a) in self.pt_entry.iter().enumerate() {
+            for b in &self.pt_entry[i + 1..] {
+                let (ra, rb) = (a.range(), b.range());
+                if ra.start < rb.end && rb.start < ra.end {
+                    return Err(Error::PartitionOverlap(a.name.clone(), b.name.clone()));
+                }
+            }
+        }
+
+        let mut segments = vec![];
+        for entry in &self.pt_entry {
+            let path = dir.join(format!("{}.bin", entry.name));
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if data.len() as u64 > entry.size0 as u64 {
+                return Err(Error::PartitionTooSmall {
+                    name: entry.name.clone(),
+                    size: entry.size0,
+                    len: data.len(),
+                });
+            }
+            log::debug!(
+                "Partition {} addr: {:x} size: {}",
+                entry.name,
+                entry.address0,
+                data.len()
+            );
+            segments.push(RomSegment::from_vec(entry.address0, data));
+        }
+        Ok(segments)
+    }
     fn header_checksum(&self) -> u32 {
         let data = self.to_bytes().unwrap();
         crc::crc32::checksum_ieee(&data[0..12])
@@ -57,6 +97,9 @@ impl PartitionCfg {
 }
 
 impl Entry {
+    fn range(&self) -> Range<u64> {
+        self.address0 as u64..self.address0 as u64 + self.size0 as u64
+    }
     fn write_name(name: &str, output: &mut BitVec<Msb0, u8>) -> Result<(), DekuError> {
         if name.len() > 8 {
             return Err(DekuError::Unexpected("name too long".to_string()));
@@ -70,3 +113,76 @@ impl Entry {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, address0: u32, size0: u32) -> Entry {
+        Entry {
+            name: name.to_string(),
+            address0,
+            size0,
+            ..Default::default()
+        }
+    }
+
+    fn cfg(entries: Vec<Entry>) -> PartitionCfg {
+        PartitionCfg {
+            pt_entry: entries,
+            ..Default::default()
+        }
+    }
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("blflash-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn overlapping_entries_are_rejected() {
+        let cfg = cfg(vec![
+            entry("FW", 0x10000, 0x10000),
+            entry("mfg", 0x30000, 0x1000),
+            entry("media", 0x1f000, 0x2000),
+        ]);
+        let result = cfg.load_segments(Path::new("/nonexistent"));
+        assert!(matches!(
+            result,
+            Err(Error::PartitionOverlap(a, b)) if a == "FW" && b == "media"
+        ));
+    }
+
+    #[test]
+    fn adjacent_entries_load_present_files() {
+        let dir = scratch_dir("adjacent");
+        fs::write(dir.join("FW.bin"), [1u8; 0x100]).unwrap();
+        let cfg = cfg(vec![
+            entry("FW", 0x10000, 0x10000),
+            entry("media", 0x20000, 0x1000),
+        ]);
+        let segments = cfg.load_segments(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].addr, 0x10000);
+        assert_eq!(segments[0].size(), 0x100);
+    }
+
+    #[test]
+    fn oversized_file_is_rejected() {
+        let dir = scratch_dir("oversized");
+        fs::write(dir.join("media.bin"), [1u8; 0x1001]).unwrap();
+        let cfg = cfg(vec![entry("media", 0x20000, 0x1000)]);
+        let result = cfg.load_segments(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::PartitionTooSmall {
+                size: 0x1000,
+                len: 0x1001,
+                ..
+            })
+        ));
+    }
+}
@@ -1,12 +1,28 @@
+mod abort;
+pub mod bundle;
 pub mod chip;
+pub mod compress;
 mod connection;
+pub mod defaults;
 pub mod elf;
 mod error;
 mod flasher;
 pub mod image;
+mod progress;
+mod tcp;
 
+pub use abort::AbortHandle;
+pub use connection::{Command, Direction, PacketHook};
 pub use error::{Error, RomError};
-pub use flasher::Flasher;
+pub use flasher::protocol;
+pub use flasher::{
+    BootInfoDecoded, BootInfoLoopReport, ChipMatch, ConnectDiagnostics, FlashParams, FlashPlan,
+    FlashReport, FlashStats, Flasher, FlasherBuilder, FlasherSession, GapFill, PlannedSegment,
+    ReadMode, RebootMethod, SegmentStat, SegmentVerify, SerialMonitor, SlotResult, TimeoutProfile,
+    VerifyMode, FLASH_STATUS_PROTECT_MASK,
+};
+pub use progress::{IndicatifReporter, LogReporter, ProgressReporter};
+pub use tcp::TcpSerial;
 
 use crate::{
     chip::{
@@ -21,6 +37,7 @@ use std::{
     borrow::Cow,
     fs::{read, File},
     path::PathBuf,
+    time::{Duration, Instant},
 };
 use structopt::StructOpt;
 
@@ -30,11 +47,25 @@ pub struct Connection {
     #[structopt(short, long)]
     pub port: String,
     /// Flash baud rate
-    #[structopt(short, long, default_value = "1000000")]
+    #[structopt(short, long, default_value = "1000000", env = defaults::BAUD_RATE_ENV)]
     pub baud_rate: usize,
     /// Initial baud rate
-    #[structopt(long, default_value = "115200")]
+    #[structopt(long, default_value = "115200", env = defaults::INITIAL_BAUD_RATE_ENV)]
     pub initial_baud_rate: usize,
+    /// Board name to apply known quirks for, e.g. pinecone
+    #[structopt(long)]
+    pub board: Option<String>,
+    /// Log the raw bytes sent and received at trace level
+    #[structopt(long)]
+    pub log_packets: bool,
+    /// Progress output: bar, log or auto to draw a bar only on a terminal
+    #[structopt(
+        long,
+        default_value = "auto",
+        possible_values = &["auto", "bar", "log"],
+        env = "BLFLASH_PROGRESS"
+    )]
+    pub progress: String,
 }
 
 #[derive(StructOpt)]
@@ -63,6 +94,15 @@ pub struct FlashOpt {
     /// Don't skip if hash matches
     #[structopt(short, long)]
     pub force: bool,
+    /// With --force, erase the whole chip once instead of each segment
+    #[structopt(long)]
+    pub chip_erase: bool,
+    /// Start the application with the reboot command instead of the reset line
+    #[structopt(long)]
+    pub reboot: bool,
+    /// Reset and print the serial console at this baud rate after flashing
+    #[structopt(long)]
+    pub monitor: Option<usize>,
     #[structopt(flatten)]
     pub boot: Boot2Opt,
 }
@@ -91,6 +131,13 @@ pub struct DumpOpt {
     /// end address
     #[structopt(parse(try_from_str = parse_int::parse), default_value = "0x100000")]
     pub end: u32,
+    /// Don't write the trailing 0xFF after the last used byte
+    #[structopt(long)]
+    pub trim: bool,
+    /// Dump from start to the end of the flash, with the size detected
+    /// from its JEDEC ID
+    #[structopt(long)]
+    pub all: bool,
 }
 
 #[derive(StructOpt)]
@@ -103,26 +150,76 @@ pub enum Opt {
     Dump(DumpOpt),
 }
 
+/// Open `path` as 8N1 without flow control.
+pub fn open_port(path: &str) -> Result<serial::SystemPort, Error> {
+    let mut serial = serial::open(path)?;
+    serial.reconfigure(&|setup: &mut dyn SerialPortSettings| {
+        setup.set_char_size(CharSize::Bits8);
+        setup.set_stop_bits(StopBits::Stop1);
+        setup.set_parity(Parity::ParityNone);
+        setup.set_flow_control(FlowControl::FlowNone);
+        Ok(())
+    })?;
+    Ok(serial)
+}
+
+/// Open `path` like [`open_port`], retrying until it can be opened, e.g.
+/// while a power cycled board's USB serial adapter is enumerated. Fails
+/// with `Error::Timeout` if it can't be opened within `timeout`.
+pub fn wait_for_port(path: &str, timeout: Duration) -> Result<serial::SystemPort, Error> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    let start = Instant::now();
+    loop {
+        match open_port(path) {
+            Ok(serial) => {
+                log::debug!("Opened {} after {:?}", path, start.elapsed());
+                return Ok(serial);
+            }
+            Err(e) if start.elapsed() < timeout => {
+                log::trace!("Waiting for {}: {}", path, e);
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            Err(e) => {
+                log::debug!("Gave up waiting for {}: {}", path, e);
+                return Err(Error::Timeout);
+            }
+        }
+    }
+}
+
 impl Connection {
-    pub fn open_serial(&self) -> Result<impl SerialPort, Error> {
-        let mut serial = serial::open(&self.port)?;
-        serial.reconfigure(&|setup: &mut dyn SerialPortSettings| {
-            setup.set_char_size(CharSize::Bits8);
-            setup.set_stop_bits(StopBits::Stop1);
-            setup.set_parity(Parity::ParityNone);
-            setup.set_flow_control(FlowControl::FlowNone);
-            Ok(())
-        })?;
-        Ok(serial)
+    pub fn open_serial(&self) -> Result<serial::SystemPort, Error> {
+        open_port(&self.port)
     }
     pub fn create_flasher(&self, chip: impl Chip + 'static) -> Result<Flasher, Error> {
+        let quirks = match &self.board {
+            Some(board) => {
+                chip::board_quirks(board).ok_or_else(|| Error::UnknownBoard(board.clone()))?
+            }
+            None => chip.quirks(),
+        };
         let serial = self.open_serial()?;
-        Flasher::connect(
-            chip,
-            serial,
-            BaudRate::from_speed(self.initial_baud_rate),
-            BaudRate::from_speed(self.baud_rate),
-        )
+        let mut builder = FlasherBuilder::new()
+            .initial_speed(BaudRate::from_speed(self.initial_baud_rate))
+            .flash_speed(BaudRate::from_speed(self.baud_rate))
+            .quirks(quirks);
+        match self.progress.as_str() {
+            "bar" => builder = builder.progress_reporter(Box::new(IndicatifReporter::default())),
+            "log" => builder = builder.progress_reporter(Box::new(LogReporter::default())),
+            _ => {}
+        }
+        if self.log_packets {
+            builder = builder.packet_hook(Box::new(|direction, data| {
+                log::trace!("{:?} {}", direction, hex::encode(data))
+            }));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            builder = builder.raw_fd(serial.as_raw_fd());
+        }
+        builder.connect(chip, serial)
     }
 }
 
@@ -153,7 +250,7 @@ impl Boot2Opt {
     }
     pub fn make_segment<'a>(
         self,
-        _chip: &'a dyn Chip,
+        chip: &'a dyn Chip,
         image: Vec<u8>,
     ) -> Result<RomSegment<'a>, Error> {
         let boot_header_cfg = self
@@ -163,6 +260,9 @@ impl Boot2Opt {
         let BootHeaderCfgFile {
             mut boot_header_cfg,
         } = toml::from_slice(&boot_header_cfg)?;
+        if let Some(caps) = chip.flash_capabilities() {
+            boot_header_cfg.validate(&caps)?;
+        }
         let img = boot_header_cfg.make_image(0x2000, image)?;
 
         Ok(RomSegment::from_vec(0x0, img))
@@ -195,24 +295,107 @@ pub fn read_image<'a>(chip: &dyn Chip, image: &'a [u8]) -> Result<Cow<'a, [u8]>,
 pub fn flash(opt: FlashOpt) -> Result<(), Error> {
     let chip = Bl602;
     let image = read(&opt.image)?;
+    let image = compress::decompress(&image)?;
     let image = read_image(&chip, &image)?;
 
     let mut flasher = opt.conn.create_flasher(chip)?;
     log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
     log::trace!("Boot info: {:x?}", flasher.boot_info());
+    flasher.verify_chip_match();
 
     let segments = opt.boot.get_segments(&chip, Vec::from(image))?;
+    flasher.set_chip_erase(opt.chip_erase);
     flasher.load_segments(opt.force, segments.into_iter())?;
-    flasher.reset()?;
+    if let Some(baud) = opt.monitor {
+        log::info!("Success, monitoring at {} baud", baud);
+        let mut monitor = flasher.into_monitor(BaudRate::from_speed(baud))?;
+        std::io::copy(&mut monitor, &mut std::io::stdout())?;
+        return Ok(());
+    }
+    if opt.reboot {
+        let method = flasher.run_flashed_app()?;
+        log::info!("Rebooted by {:?}", method);
+    } else {
+        match flasher.reset() {
+            Err(Error::ResetUnavailable) => log::info!("Reset the board to run the firmware"),
+            result => result?,
+        }
+    }
 
     log::info!("Success");
 
     Ok(())
 }
 
+/// Options for [`flash_image`]
+pub struct FlashImageOptions {
+    pub initial_baud_rate: usize,
+    pub baud_rate: usize,
+    /// Don't skip segments whose hash already matches
+    pub force: bool,
+    /// Compare the sha256 of each written segment
+    pub verify: bool,
+    /// Reset the device when done
+    pub reset: bool,
+    /// Initialize the flash between segments
+    pub gap_fill: Option<GapFill>,
+}
+
+impl Default for FlashImageOptions {
+    fn default() -> Self {
+        FlashImageOptions {
+            initial_baud_rate: 115200,
+            baud_rate: 1000000,
+            force: false,
+            verify: true,
+            reset: true,
+            gap_fill: None,
+        }
+    }
+}
+
+/// Connect, flash an elf or bin image with the default boot2 layout,
+/// verify and reset in one call.
+pub fn flash_image(
+    port: &str,
+    chip: impl Chip + Copy + 'static,
+    image: &[u8],
+    options: FlashImageOptions,
+) -> Result<FlashStats, Error> {
+    let image = compress::decompress(image)?;
+    let image = read_image(&chip, &image)?;
+    let conn = Connection {
+        port: port.to_string(),
+        baud_rate: options.baud_rate,
+        initial_baud_rate: options.initial_baud_rate,
+        board: None,
+        log_packets: false,
+        progress: "auto".to_string(),
+    };
+    let mut flasher = conn.create_flasher(chip)?;
+    flasher.verify_chip_match();
+    flasher.set_verify(options.verify);
+    flasher.set_gap_fill(options.gap_fill);
+
+    let boot = Boot2Opt {
+        partition_cfg: None,
+        boot_header_cfg: None,
+        dtb: None,
+        without_boot2: false,
+    };
+    let segments = boot.get_segments(&chip, Vec::from(image))?;
+    let report = flasher.flash_segments(options.force, segments.into_iter())?;
+    if options.reset {
+        flasher.reset()?;
+    }
+
+    Ok(report.stats())
+}
+
 pub fn check(opt: CheckOpt) -> Result<(), Error> {
     let chip = Bl602;
     let image = read(&opt.image)?;
+    let image = compress::decompress(&image)?;
     let image = read_image(&chip, &image)?;
 
     let mut flasher = opt.conn.create_flasher(Bl602)?;
@@ -232,7 +415,18 @@ pub fn dump(opt: DumpOpt) -> Result<(), Error> {
     log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
     log::trace!("Boot info: {:x?}", flasher.boot_info());
 
-    flasher.dump_flash(opt.start..opt.end, &mut output)?;
+    let end = if opt.all {
+        let size = flasher.detect_flash_size()?;
+        log::info!("Detected flash size: {:#x}", size);
+        size
+    } else {
+        opt.end
+    };
+    if opt.trim {
+        flasher.dump_flash_trim(opt.start..end, &mut output)?;
+    } else {
+        flasher.dump_flash(opt.start..end, &mut output)?;
+    }
 
     log::info!("Success");
 
@@ -0,0 +1,177 @@
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use std::cell::RefCell;
+use std::env;
+use std::io::{stderr, IsTerminal};
+use std::rc::Rc;
+
+/// Set to `bar` or `log` to override progress output detection
+pub const PROGRESS_ENV: &str = "BLFLASH_PROGRESS";
+
+/// Receives the progress of flashing, verifying and dumping, so embedders
+/// can draw their own progress UI.
+pub trait ProgressReporter {
+    fn start(&mut self, total: u64);
+    fn inc(&mut self, delta: u64);
+    fn finish(&mut self);
+}
+
+/// Draws an indicatif progress bar, the CLI default
+#[derive(Default)]
+pub struct IndicatifReporter {
+    bar: Option<ProgressBar>,
+}
+
+impl ProgressReporter for IndicatifReporter {
+    fn start(&mut self, total: u64) {
+        let bar = ProgressBar::new(total);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("  {wide_bar} {bytes}/{total_bytes} {bytes_per_sec} {eta}  ")
+                .progress_chars("#>-"),
+        );
+        self.bar = Some(bar);
+    }
+
+    fn inc(&mut self, delta: u64) {
+        if let Some(bar) = &self.bar {
+            bar.inc(delta);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// Logs a line every 10%, used when stderr is not a terminal so piped and
+/// CI output stays readable.
+#[derive(Default)]
+pub struct LogReporter {
+    len: u64,
+    pos: u64,
+    step: u64,
+}
+
+impl ProgressReporter for LogReporter {
+    fn start(&mut self, total: u64) {
+        *self = LogReporter {
+            len: total,
+            ..Default::default()
+        };
+    }
+
+    fn inc(&mut self, delta: u64) {
+        self.pos += delta;
+        if self.len == 0 {
+            return;
+        }
+        let step = (self.pos * 10 / self.len).min(10);
+        if step > self.step {
+            self.step = step;
+            log::info!(
+                "{}% {}/{}",
+                self.pos * 100 / self.len,
+                HumanBytes(self.pos),
+                HumanBytes(self.len)
+            );
+        }
+    }
+
+    fn finish(&mut self) {}
+}
+
+/// A user supplied reporter shared by every operation of a `Flasher`
+#[derive(Clone)]
+pub(crate) struct SharedReporter(pub Rc<RefCell<Box<dyn ProgressReporter>>>);
+
+impl ProgressReporter for SharedReporter {
+    fn start(&mut self, total: u64) {
+        self.0.borrow_mut().start(total)
+    }
+
+    fn inc(&mut self, delta: u64) {
+        self.0.borrow_mut().inc(delta)
+    }
+
+    fn finish(&mut self) {
+        self.0.borrow_mut().finish()
+    }
+}
+
+/// Progress of one operation, batching updates to its reporter
+pub struct Progress {
+    reporter: Option<Box<dyn ProgressReporter>>,
+    /// Update the reporter every `batch` increments
+    batch: u32,
+    count: u32,
+    pending: u64,
+}
+
+impl Progress {
+    /// Progress on an indicatif bar, or log lines if stderr is not a terminal
+    pub fn new(len: u64) -> Self {
+        let use_bar = match env::var(PROGRESS_ENV).as_deref() {
+            Ok("bar") => true,
+            Ok("log") => false,
+            _ => stderr().is_terminal(),
+        };
+        if use_bar {
+            Self::with_reporter(Box::new(IndicatifReporter::default()), len)
+        } else {
+            Self::with_reporter(Box::new(LogReporter::default()), len)
+        }
+    }
+
+    pub fn with_reporter(mut reporter: Box<dyn ProgressReporter>, len: u64) -> Self {
+        reporter.start(len);
+        Progress {
+            reporter: Some(reporter),
+            batch: 1,
+            count: 0,
+            pending: 0,
+        }
+    }
+
+    /// Progress that is not shown at all
+    pub fn hidden() -> Self {
+        Progress {
+            reporter: None,
+            batch: 1,
+            count: 0,
+            pending: 0,
+        }
+    }
+
+    /// Only update the reporter every `batch` increments, which saves
+    /// measurable overhead per chunk at very high baud rates.
+    pub fn batched(mut self, batch: u32) -> Self {
+        self.batch = batch.max(1);
+        self
+    }
+
+    pub fn inc(&mut self, delta: u64) {
+        self.pending += delta;
+        self.count += 1;
+        if self.count >= self.batch {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        let delta = self.pending;
+        self.pending = 0;
+        self.count = 0;
+        if let Some(reporter) = &mut self.reporter {
+            reporter.inc(delta);
+        }
+    }
+
+    pub fn finish_and_clear(&mut self) {
+        self.flush();
+        if let Some(reporter) = &mut self.reporter {
+            reporter.finish();
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Serial port over a TCP serial bridge such as ser2net.
+use serial::{
+    core::{Error as SerialError, ErrorKind as SerialErrorKind},
+    BaudRate, CharSize, FlowControl, Parity, PortSettings, SerialPort, SerialPortSettings,
+    StopBits,
+};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// A [`SerialPort`] forwarding data over a TCP connection.
+///
+/// The baud rate is recorded but not applied, the bridge's own serial
+/// settings are used. The modem control lines are not available, so the
+/// device can't be reset: connect with `ResetStrategy::Manual` and put it
+/// into the bootrom by hand.
+pub struct TcpSerial {
+    stream: TcpStream,
+    timeout: Duration,
+    settings: PortSettings,
+}
+
+impl TcpSerial {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::new(TcpStream::connect(addr)?)
+    }
+
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let mut serial = TcpSerial {
+            stream,
+            timeout: Duration::from_millis(100),
+            settings: PortSettings {
+                baud_rate: BaudRate::Baud115200,
+                char_size: CharSize::Bits8,
+                parity: Parity::ParityNone,
+                stop_bits: StopBits::Stop1,
+                flow_control: FlowControl::FlowNone,
+            },
+        };
+        serial.apply_timeout()?;
+        Ok(serial)
+    }
+
+    fn apply_timeout(&mut self) -> io::Result<()> {
+        // a zero timeout is rejected by the socket
+        let timeout = self.timeout.max(Duration::from_millis(1));
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.set_write_timeout(Some(timeout))
+    }
+
+    fn no_modem_lines() -> SerialError {
+        SerialError::new(
+            SerialErrorKind::InvalidInput,
+            "modem control lines are not available over TCP",
+        )
+    }
+}
+
+impl Read for TcpSerial {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            // unix reports an expired read timeout as WouldBlock
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, e))
+            }
+            result => result,
+        }
+    }
+}
+
+impl Write for TcpSerial {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl SerialPort for TcpSerial {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serial::Result<()> {
+        self.timeout = timeout;
+        Ok(self.apply_timeout()?)
+    }
+
+    fn configure(&mut self, settings: &PortSettings) -> serial::Result<()> {
+        self.settings = *settings;
+        Ok(())
+    }
+
+    fn reconfigure(
+        &mut self,
+        setup: &dyn Fn(&mut dyn SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        setup(&mut self.settings)
+    }
+
+    fn set_rts(&mut self, _level: bool) -> serial::Result<()> {
+        Err(Self::no_modem_lines())
+    }
+
+    fn set_dtr(&mut self, _level: bool) -> serial::Result<()> {
+        Err(Self::no_modem_lines())
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        Err(Self::no_modem_lines())
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        Err(Self::no_modem_lines())
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        Err(Self::no_modem_lines())
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        Err(Self::no_modem_lines())
+    }
+}
@@ -17,6 +17,15 @@ struct BlflashOpt {
     /// Don't skip if hash matches
     #[structopt(short, long)]
     force: bool,
+    /// With --force, erase the whole chip once instead of each segment
+    #[structopt(long)]
+    chip_erase: bool,
+    /// Start the application with the reboot command instead of the reset line
+    #[structopt(long)]
+    reboot: bool,
+    /// Reset and print the serial console at this baud rate after flashing
+    #[structopt(long)]
+    monitor: Option<usize>,
     #[structopt(flatten)]
     boot: Boot2Opt,
     #[structopt(long)]
@@ -48,6 +57,9 @@ fn blflash_main(args: BlflashOpt) -> Result<()> {
         conn: args.conn,
         image: path,
         force: args.force,
+        chip_erase: args.chip_erase,
+        reboot: args.reboot,
+        monitor: args.monitor,
         boot: args.boot,
     };
 